@@ -0,0 +1,54 @@
+//! Measures `run` used as a far jump on a large program segment.
+//!
+//! `run` shares the source segment copy-on-write, so the cost of a jump no
+//! longer grows with the size of the program. The eager copy that `run` used
+//! to make is timed alongside it for comparison.
+//!
+//! Run with `cargo bench --bench load_program`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use warch::cpu::{CPU, CPU_Opcode};
+use warch::ram::RAM;
+
+const PROGRAM_WORDS: usize = 64 * 1024;
+const JUMPS: u32 = 10_000;
+
+fn time_jumps() -> Duration {
+    let mut cpu = CPU::new(1, 32, 8);
+    let mut ram = RAM::new();
+
+    ram.request_segment(PROGRAM_WORDS).unwrap();
+    ram.request_segment(PROGRAM_WORDS).unwrap();
+
+    let set_segment = cpu.build_lv_inst(1, 1);
+    let jump = cpu.build_instruction(CPU_Opcode::LP, 0, 1, 2);
+
+    let start = Instant::now();
+    cpu.compute(&mut ram, set_segment).unwrap();
+    for _ in 0..JUMPS {
+        cpu.compute(&mut ram, jump).unwrap();
+    }
+    start.elapsed()
+}
+
+fn time_eager_copies() -> Duration {
+    let mut ram = RAM::new();
+    ram.request_segment(PROGRAM_WORDS).unwrap();
+
+    let start = Instant::now();
+    for _ in 0..JUMPS {
+        black_box(ram.to_vec(0));
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let cow = time_jumps();
+    let eager = time_eager_copies();
+
+    println!("{JUMPS} jumps into a {PROGRAM_WORDS} word segment");
+    println!("copy-on-write: {:?} ({:?} per jump)", cow, cow / JUMPS);
+    println!("eager copy:    {:?} ({:?} per jump)", eager, eager / JUMPS);
+    println!("speedup:       {:.1}x", eager.as_secs_f64() / cow.as_secs_f64());
+}
@@ -0,0 +1,64 @@
+; Draws a colour pattern with the GPU.
+;
+; The CPU copies the GPU program at the end of this file into the GPU's
+; display data RAM through the GPU's ports, passes it the number of
+; framebuffer words (100x100 pixels, three words each) and a colour step in
+; its registers, then starts it and waits for it to finish.
+
+        movi r1, gpu_end - gpu_program
+        movi r2, 0x17           ; map a segment of that many words
+        portout r2, r1
+        movi r1, gpu_end
+        movi r3, gpu_program
+        movi r4, 0x16           ; and write the program into it
+copy:   load r5, r0, r3
+        portout r4, r5
+        movi r5, 1
+        add r3, r3, r5
+        sub r5, r1, r3
+        jnz r5, copy
+
+        movi r2, 0x14           ; the segment just written
+        portin r5, r2
+        movi r2, 0x19           ; becomes the GPU's program
+        portout r2, r5
+        movi r2, 0x18           ; and is unmapped again
+        portout r2, r5
+
+        movi r2, 0x12           ; gpu r1 = words to fill
+        movi r5, 1
+        portout r2, r5
+        movi r2, 0x13
+        movi r5, 30000
+        portout r2, r5
+        movi r2, 0x12           ; gpu r3 = colour step
+        movi r5, 3
+        portout r2, r5
+        movi r2, 0x13
+        movi r5, 7
+        portout r2, r5
+
+        movi r2, 0x11           ; start at the GPU program's first word
+        portout r2, r0
+        movi r2, 0x10
+        movi r5, 1
+        portout r2, r5
+        movi r2, 0x1a           ; wait for it, r5 = 0 if it finished
+        portin r5, r2
+        halt
+
+; The GPU program, fills d[1] from the end, writing index * step to each
+; word.
+gpu_program:
+        .word 0xf0000000        ; movi r0, 0
+        .word 0xf8000001        ; movi r4, 1           the framebuffer
+        .word 0xe0000140        ; nand r5, r0, r0      r5 = -1
+        .word 0xfc000004        ; movi r6, 4           the loop
+        .word 0xb000004d        ; add r1, r1, r5
+        .word 0xc000000b        ; mul r0, r1, r3
+        .word 0x90000108        ; store r4, r1, r0
+        .word 0xfe00000a        ; movi r7, 10          the end
+        .word 0xa00001f1        ; cmov r7, r6, r1      or the loop while r1 != 0
+        .word 0x40000007        ; jump r7
+        .word 0x40000007        ; jump r7              to itself, finishing
+gpu_end:
@@ -1,12 +1,66 @@
-use crate::cpu::CPU;
-use crate::gpu::GPU;
-use crate::harddrive::HardDrive;
-use crate::ram::RAM;
-use crate::screen::Screen;
-
-pub enum MachinePart{
-    CPU(CPU),
-    RAM(RAM),
-    Storage(HardDrive),
-    GPU(GPU)
+use std::any::Any;
+use std::ops::Range;
+
+/// A named value captured by `MachinePart::snapshot`.
+pub type StateValue = (String, u64);
+
+/// A part that can be plugged into a `Machine`.
+///
+/// Apart from `name` and the `Any` conversions, every method has a default
+/// so simple devices only implement what they need. Parts that expose I/O
+/// ports claim a range of port numbers with `ports`; the machine routes
+/// reads and writes in that range to the part.
+pub trait MachinePart: Any + Send {
+    /// A short name identifying the part, e.g. "cpu" or "ram".
+    fn name(&self) -> &str;
+
+    /// Returns the part to its power-on state.
+    fn reset(&mut self) {}
+
+    /// Advances the part by `cycles` CPU cycles.
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// The I/O ports handled by this part.
+    fn ports(&self) -> Range<u64> {
+        0..0
+    }
+
+    /// Reads the I/O port `port`, which is inside `ports`.
+    fn port_read(&mut self, _port: u64) -> u64 {
+        0
+    }
+
+    /// Writes `value` to the I/O port `port`, which is inside `ports`.
+    fn port_write(&mut self, _port: u64, _value: u64) {}
+
+    /// The part's current state as named values, for debugging and tests.
+    fn snapshot(&self) -> Vec<StateValue> {
+        Vec::new()
+    }
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
 }
+
+/// Everything the CPU reaches besides its RAM: the I/O ports of the other
+/// parts, read and written by `portin` and `portout`, and their clocks.
+///
+/// The defaults have no ports and no clocks, which is what `()` provides.
+pub trait Bus {
+    /// Reads the I/O port `port`, or None if no part handles it.
+    fn port_read(&mut self, _port: u64) -> Option<u64> {
+        None
+    }
+
+    /// Writes `value` to the I/O port `port`. Returns false if no part
+    /// handles it.
+    fn port_write(&mut self, _port: u64, _value: u64) -> bool {
+        false
+    }
+
+    /// Advances every part by `cycles` CPU cycles.
+    fn tick(&mut self, _cycles: u64) {}
+}
+
+impl Bus for () {}
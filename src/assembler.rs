@@ -0,0 +1,882 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use crate::cpu::CPU_Opcode;
+use crate::disassembler::disassemble_instruction;
+
+/// An error in assembler source, with the line it was found on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+fn error<T>(line: usize, message: String) -> Result<T, AsmError> {
+    Err(AsmError { line, message })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Comma,
+    Colon,
+    Plus,
+    Minus
+}
+
+/// A value made of numbers and labels added together.
+#[derive(Clone, Debug)]
+struct Expr {
+    terms: Vec<(i64, Term)>,
+    part: Part
+}
+
+/// Which half of a 32-bit value an `Expr` stands for. `li32` loads wide
+/// constants 16 bits at a time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Whole,
+    High,
+    Low
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+    Number(i64),
+    Label(String)
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Register(usize),
+    Value(Expr)
+}
+
+#[derive(Clone, Debug)]
+enum Item {
+    Instruction(CPU_Opcode, Vec<Operand>),
+    Words(Vec<Expr>),
+    Zero(usize),
+    /// A pseudo-instruction, as the real instructions it expands into.
+    Expansion(Vec<Item>)
+}
+
+/// A source line that emits something, and where it went in the image.
+#[derive(Clone, Debug)]
+struct Statement {
+    line: usize,
+    address: usize,
+    text: String,
+    item: Item
+}
+
+impl Item {
+    fn size(&self) -> usize {
+        match self{
+            Item::Instruction(..) => { 1 },
+            Item::Words(words) => { words.len() },
+            Item::Zero(count) => { *count },
+            Item::Expansion(items) => { items.iter().map(Item::size).sum() }
+        }
+    }
+}
+
+impl Expr {
+    fn number(n: i64) -> Self {
+        Expr { terms: vec![(1, Term::Number(n))], part: Part::Whole }
+    }
+
+    /// The value of the expression if it has no labels in it.
+    fn constant(&self) -> Option<i64> {
+        let mut value: i64 = 0;
+        for (sign, term) in self.terms.iter() {
+            match term {
+                Term::Number(n) => { value = value.wrapping_add(sign * n) },
+                Term::Label(_) => { return None }
+            }
+        }
+        Some(value)
+    }
+}
+
+fn escape(c: char, line: usize) -> Result<u8, AsmError> {
+    match c{
+        'n' => { Ok(b'\n') },
+        't' => { Ok(b'\t') },
+        'r' => { Ok(b'\r') },
+        '0' => { Ok(0) },
+        '\\' | '\'' | '"' => { Ok(c as u8) },
+        _ => { error(line, format!("unknown escape `\\{c}`")) }
+    }
+}
+
+/// Reads the characters of a char or string literal up to `quote`.
+fn literal(chars: &mut std::iter::Peekable<std::str::Chars>, quote: char, line: usize) -> Result<Vec<u8>, AsmError> {
+    let mut bytes = Vec::new();
+    loop {
+        match chars.next() {
+            None => { return error(line, format!("unterminated {quote}literal{quote}")) }
+            Some(c) if c == quote => { return Ok(bytes) }
+            Some('\\') => {
+                let c = chars.next().ok_or(AsmError { line, message: String::from("unterminated escape") })?;
+                bytes.push(escape(c, line)?);
+            }
+            Some(c) if c.is_ascii() => { bytes.push(c as u8) }
+            Some(c) => { return error(line, format!("`{c}` is not an ASCII character")) }
+        }
+    }
+}
+
+fn parse_number(text: &str, line: usize) -> Result<i64, AsmError> {
+    let cleaned = text.replace('_', "");
+    let parsed = if let Some(hex) = cleaned.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    }
+    else if let Some(binary) = cleaned.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    }
+    else {
+        cleaned.parse::<i64>()
+    };
+    parsed.or_else(|_| error(line, format!("invalid number `{text}`")))
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c{
+            ';' => { break }
+            c if c.is_whitespace() => { chars.next(); }
+            ',' => { chars.next(); tokens.push(Token::Comma); }
+            ':' => { chars.next(); tokens.push(Token::Colon); }
+            '+' => { chars.next(); tokens.push(Token::Plus); }
+            '-' => { chars.next(); tokens.push(Token::Minus); }
+            '\'' => {
+                chars.next();
+                let bytes = literal(&mut chars, '\'', line)?;
+                if bytes.len() != 1 {
+                    return error(line, String::from("character literals hold exactly one character"));
+                }
+                tokens.push(Token::Number(bytes[0] as i64));
+            }
+            '"' => {
+                chars.next();
+                tokens.push(Token::Str(literal(&mut chars, '"', line)?));
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                        word.push(c);
+                        chars.next();
+                    }
+                    else {
+                        break;
+                    }
+                }
+                if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    tokens.push(Token::Number(parse_number(&word, line)?));
+                }
+                else {
+                    tokens.push(Token::Ident(word));
+                }
+            }
+            c => { return error(line, format!("unexpected character `{c}`")) }
+        }
+    }
+    Ok(tokens)
+}
+
+fn register(name: &str) -> Option<usize> {
+    let index = name.strip_prefix('r')?.parse::<usize>().ok()?;
+    if index < 8 { Some(index) } else { None }
+}
+
+fn opcode(mnemonic: &str) -> Option<CPU_Opcode> {
+    [
+        CPU_Opcode::CMov, CPU_Opcode::Load, CPU_Opcode::Store, CPU_Opcode::Add,
+        CPU_Opcode::Mul, CPU_Opcode::Div, CPU_Opcode::NAND, CPU_Opcode::HALT,
+        CPU_Opcode::MapSeg, CPU_Opcode::UnmapSeg, CPU_Opcode::Out, CPU_Opcode::In,
+        CPU_Opcode::LP, CPU_Opcode::LV, CPU_Opcode::PortIn, CPU_Opcode::PortOut
+    ].into_iter().find(|op| op.mnemonic() == mnemonic)
+}
+
+/// Splits the tokens after a mnemonic or directive at the commas.
+fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    tokens.split(|token| *token == Token::Comma).collect()
+}
+
+fn parse_expr(tokens: &[Token], line: usize) -> Result<Expr, AsmError> {
+    let mut terms = Vec::new();
+    let mut sign = 1;
+    let mut expect_term = true;
+
+    for token in tokens {
+        match (token, expect_term) {
+            (Token::Minus, true) => { sign = -sign; }
+            (Token::Plus, true) => {}
+            (Token::Number(n), true) => {
+                terms.push((sign, Term::Number(*n)));
+                expect_term = false;
+            }
+            (Token::Ident(name), true) if register(name).is_none() => {
+                terms.push((sign, Term::Label(name.clone())));
+                expect_term = false;
+            }
+            (Token::Plus, false) => { sign = 1; expect_term = true; }
+            (Token::Minus, false) => { sign = -1; expect_term = true; }
+            _ => { return error(line, String::from("expected a number, character or label")) }
+        }
+    }
+    if expect_term {
+        return error(line, String::from("expected a number, character or label"));
+    }
+    Ok(Expr { terms, part: Part::Whole })
+}
+
+fn parse_operand(tokens: &[Token], line: usize) -> Result<Operand, AsmError> {
+    if let [Token::Ident(name)] = tokens {
+        if let Some(index) = register(name) {
+            return Ok(Operand::Register(index));
+        }
+    }
+    Ok(Operand::Value(parse_expr(tokens, line)?))
+}
+
+/// Parses a directive into the item it emits.
+fn parse_directive(name: &str, tokens: &[Token], line: usize) -> Result<Item, AsmError> {
+    match name{
+        ".word" => {
+            let words = split_operands(tokens).into_iter()
+                .map(|operand| parse_expr(operand, line))
+                .collect::<Result<Vec<_>, _>>()?;
+            if words.is_empty() {
+                return error(line, String::from(".word needs at least one value"));
+            }
+            Ok(Item::Words(words))
+        }
+        ".ascii" | ".asciz" => {
+            let mut bytes = match tokens {
+                [Token::Str(bytes)] => { bytes.clone() },
+                _ => { return error(line, format!("{name} needs one string")) }
+            };
+            if name == ".asciz" {
+                bytes.push(0);
+            }
+            // One character per word, the unit `output` prints.
+            Ok(Item::Words(bytes.into_iter()
+                .map(|b| Expr::number(b as i64))
+                .collect()))
+        }
+        ".zero" => {
+            match tokens {
+                [Token::Number(count)] if *count >= 0 => { Ok(Item::Zero(*count as usize)) },
+                _ => { error(line, String::from(".zero needs a word count")) }
+            }
+        }
+        _ => { error(line, format!("unknown directive `{name}`")) }
+    }
+}
+
+/// Parses the operands of `.pseudo`: the link register and the two scratch
+/// registers, which must be different registers other than r0.
+fn parse_pseudo_registers(tokens: &[Token], line: usize) -> Result<PseudoRegisters, AsmError> {
+    let operands = split_operands(tokens).into_iter()
+        .map(|operand| parse_operand(operand, line))
+        .collect::<Result<Vec<_>, _>>()?;
+    match operands.as_slice() {
+        [Operand::Register(link), Operand::Register(scratch), Operand::Register(at)]
+            if ![*link, *scratch, *at].contains(&ZERO) && link != scratch && link != at && scratch != at => {
+            Ok(PseudoRegisters { link: *link, scratch: *scratch, at: *at })
+        }
+        _ => { error(line, String::from(".pseudo needs three different registers other than r0")) }
+    }
+}
+
+/// Parses a mnemonic and its operands, checking the operand count.
+/// Pseudo-instructions are expanded here, at `address`.
+fn parse_instruction(mnemonic: &str, tokens: &[Token], address: usize, registers: PseudoRegisters, line: usize) -> Result<Item, AsmError> {
+    let mnemonic = mnemonic.to_lowercase();
+    let operands = split_operands(tokens).into_iter()
+        .map(|operand| parse_operand(operand, line))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if PSEUDO_INSTRUCTIONS.contains(&mnemonic.as_str()) {
+        return expand(&mnemonic, operands, address, registers, line).map(Item::Expansion);
+    }
+    let op = opcode(&mnemonic)
+        .ok_or(AsmError { line, message: format!("unknown instruction `{mnemonic}`") })?;
+
+    let expected = match op{
+        CPU_Opcode::HALT => { 0 },
+        CPU_Opcode::UnmapSeg | CPU_Opcode::Out | CPU_Opcode::In => { 1 },
+        CPU_Opcode::MapSeg | CPU_Opcode::LP | CPU_Opcode::LV |
+        CPU_Opcode::PortIn | CPU_Opcode::PortOut => { 2 },
+        _ => { 3 }
+    };
+    if operands.len() != expected {
+        return error(line, format!("{} takes {} operands, found {}", op.mnemonic(), expected, operands.len()));
+    }
+    Ok(Item::Instruction(op, operands))
+}
+
+/// Pseudo-instructions, which expand into several real instructions.
+///
+/// They follow a register convention: r0 must hold 0 (as it does when the
+/// machine starts), two scratch registers are overwritten by the expansions,
+/// and `call` keeps the return address in a link register. These are r6, r7
+/// and r5 unless a `.pseudo` directive picks others.
+pub const PSEUDO_INSTRUCTIONS: [&str; 13] = [
+    "li32", "sub", "not", "and", "or", "xor", "shl", "shr", "jmp", "jz", "jnz", "call", "ret"
+];
+
+const ZERO: usize = 0;
+
+/// The registers pseudo-instructions use besides r0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct PseudoRegisters {
+    /// Holds the return address of `call`.
+    link: usize,
+    scratch: usize,
+    at: usize
+}
+
+/// The default convention. These overlap the registers a trap handler gets
+/// the trap frame in, so a handler that uses pseudo-instructions should
+/// move them with `.pseudo`.
+const DEFAULT_PSEUDO_REGISTERS: PseudoRegisters = PseudoRegisters { link: 5, scratch: 6, at: 7 };
+
+fn instruction(op: CPU_Opcode, registers: &[usize]) -> Item {
+    Item::Instruction(op, registers.iter().map(|r| Operand::Register(*r)).collect())
+}
+
+fn movi(register: usize, value: Expr) -> Item {
+    Item::Instruction(CPU_Opcode::LV, vec![Operand::Register(register), Operand::Value(value)])
+}
+
+fn nand(ra: usize, rb: usize, rc: usize) -> Item {
+    instruction(CPU_Opcode::NAND, &[ra, rb, rc])
+}
+
+fn jump(target: Expr, at: usize) -> Vec<Item> {
+    vec![movi(at, target), instruction(CPU_Opcode::LP, &[ZERO, at])]
+}
+
+/// Splits pseudo-instruction operands into registers and an optional value,
+/// checking them against `shape`, e.g. "rrr" or "rv".
+fn pseudo_operands(mnemonic: &str, operands: Vec<Operand>, shape: &str, pseudo: PseudoRegisters, line: usize) -> Result<(Vec<usize>, Option<Expr>), AsmError> {
+    let mut registers = Vec::new();
+    let mut value = None;
+    let fits = operands.len() == shape.len() && operands.into_iter().zip(shape.chars()).all(|(operand, kind)| {
+        match (operand, kind) {
+            (Operand::Register(r), 'r') => { registers.push(r); true }
+            (Operand::Value(expr), 'v') => { value = Some(expr); true }
+            _ => { false }
+        }
+    });
+
+    if !fits {
+        let expected: Vec<&str> = shape.chars().map(|kind| if kind == 'r' { "register" } else { "value" }).collect();
+        return error(line, format!("{mnemonic} takes {}", if expected.is_empty() { String::from("no operands") } else { expected.join(", ") }));
+    }
+    let PseudoRegisters { scratch, at, .. } = pseudo;
+    if registers.iter().any(|r| *r == scratch || *r == at) {
+        return error(line, format!("{mnemonic} cannot use r{scratch} or r{at}, which pseudo-instructions use as scratch registers"));
+    }
+    Ok((registers, value))
+}
+
+/// The number of bits to shift by, which must be a constant from 0 to 31.
+fn shift_amount(expr: &Expr, line: usize) -> Result<u32, AsmError> {
+    match expr.constant() {
+        Some(n) if (0..32).contains(&n) => { Ok(n as u32) },
+        _ => { error(line, String::from("shift amounts must be a number from 0 to 31")) }
+    }
+}
+
+/// Expands a pseudo-instruction at `address` into real instructions.
+fn expand(mnemonic: &str, operands: Vec<Operand>, address: usize, registers: PseudoRegisters, line: usize) -> Result<Vec<Item>, AsmError> {
+    let shape = match mnemonic{
+        "li32" | "jz" | "jnz" => { "rv" },
+        "not" => { "rr" },
+        "shl" | "shr" => { "rrv" },
+        "jmp" | "call" => { "v" },
+        "ret" => { "" },
+        _ => { "rrr" }
+    };
+    let (r, value) = pseudo_operands(mnemonic, operands, shape, registers, line)?;
+    let PseudoRegisters { link, scratch, at } = registers;
+
+    let items = match mnemonic{
+        "li32" => {
+            let value = value.unwrap();
+            match value.constant() {
+                Some(n) if (0..1 << 25).contains(&n) => { vec![movi(r[0], value)] }
+                _ => {
+                    vec![
+                        movi(r[0], Expr { part: Part::High, ..value.clone() }),
+                        movi(at, Expr::number(0x10000)),
+                        instruction(CPU_Opcode::Mul, &[r[0], r[0], at]),
+                        movi(at, Expr { part: Part::Low, ..value }),
+                        instruction(CPU_Opcode::Add, &[r[0], r[0], at])
+                    ]
+                }
+            }
+        }
+        // b - c = b + !c + 1
+        "sub" => {
+            vec![
+                nand(at, r[2], r[2]),
+                instruction(CPU_Opcode::Add, &[r[0], r[1], at]),
+                movi(at, Expr::number(1)),
+                instruction(CPU_Opcode::Add, &[r[0], r[0], at])
+            ]
+        }
+        "not" => { vec![nand(r[0], r[1], r[1])] }
+        "and" => { vec![nand(r[0], r[1], r[2]), nand(r[0], r[0], r[0])] }
+        "or" => { vec![nand(at, r[1], r[1]), nand(r[0], r[2], r[2]), nand(r[0], at, r[0])] }
+        // b ^ c = !(!(b & t) & !(c & t)) with t = !(b & c). The destination
+        // is written first with whichever source isn't needed afterwards.
+        "xor" => {
+            let (a, b, c) = (r[0], r[1], r[2]);
+            if a != c {
+                vec![nand(at, b, c), nand(a, b, at), nand(at, c, at), nand(a, a, at)]
+            }
+            else if a != b {
+                vec![nand(at, b, c), nand(a, c, at), nand(at, b, at), nand(a, a, at)]
+            }
+            else {
+                // x ^ x = 0
+                vec![nand(at, a, a), nand(a, a, at), nand(a, a, a)]
+            }
+        }
+        // Shifts multiply or divide by a power of two. movi only holds 25
+        // bits, so shifts of 25 or more are done in two steps.
+        "shl" | "shr" => {
+            let n = shift_amount(&value.unwrap(), line)?;
+            let op = if mnemonic == "shl" { CPU_Opcode::Mul } else { CPU_Opcode::Div };
+            if n < 25 {
+                vec![movi(at, Expr::number(1 << n)), instruction(op, &[r[0], r[1], at])]
+            }
+            else {
+                vec![
+                    movi(at, Expr::number(1 << 12)),
+                    instruction(op, &[r[0], r[1], at]),
+                    movi(at, Expr::number(1 << (n - 12))),
+                    instruction(op, &[r[0], r[0], at])
+                ]
+            }
+        }
+        "jmp" => { jump(value.unwrap(), at) }
+        // cmov replaces the jump target with the fall-through address when
+        // the tested register is not zero, or the other way around for jnz.
+        "jz" | "jnz" => {
+            let next = Expr::number(address as i64 + 4);
+            let (target, other) = if mnemonic == "jz" { (value.unwrap(), next) } else { (next, value.unwrap()) };
+            vec![
+                movi(at, target),
+                movi(scratch, other),
+                instruction(CPU_Opcode::CMov, &[at, scratch, r[0]]),
+                instruction(CPU_Opcode::LP, &[ZERO, at])
+            ]
+        }
+        "call" => {
+            let mut items = vec![movi(link, Expr::number(address as i64 + 3))];
+            items.extend(jump(value.unwrap(), at));
+            items
+        }
+        "ret" => { vec![instruction(CPU_Opcode::LP, &[ZERO, link])] }
+        _ => { unreachable!() }
+    };
+    Ok(items)
+}
+
+/// An assembled program: the words of the image, and the labels defined
+/// in the source with their addresses.
+pub struct Assembly {
+    pub words: Vec<u32>,
+    pub labels: HashMap<String, usize>,
+    statements: Vec<Statement>
+}
+
+impl Assembly {
+    /// The image as big-endian bytes, the format `HardDrive::from_file` loads.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    /// A listing in the same format as the disassembler's, with the
+    /// address, raw word and disassembly of every word. Each pseudo-instruction
+    /// is shown as a comment followed by the instructions it expanded into.
+    pub fn listing(&self) -> String {
+        let mut by_address: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+        for (name, address) in self.labels.iter() {
+            by_address.entry(*address).or_default().push(name);
+        }
+        for names in by_address.values_mut() {
+            names.sort();
+        }
+        let mut labels = by_address.into_iter().peekable();
+        let mut listing = String::new();
+
+        for statement in self.statements.iter() {
+            while let Some((_, names)) = labels.next_if(|(address, _)| *address <= statement.address) {
+                for name in names {
+                    listing.push_str(&format!("{name}:\n"));
+                }
+            }
+
+            let words = &self.words[statement.address..statement.address + statement.item.size()];
+            let mut address = statement.address;
+            match statement.item {
+                Item::Instruction(..) => {
+                    listing.push_str(&format!("{:08x}: {:08x}  {}\n", address, words[0], disassemble_instruction(words[0])));
+                }
+                Item::Words(_) | Item::Zero(_) => {
+                    for word in words {
+                        listing.push_str(&format!("{:08x}: {:08x}  .word {:#010x}\n", address, word, word));
+                        address += 1;
+                    }
+                }
+                Item::Expansion(_) => {
+                    listing.push_str(&format!("{:20}; {}\n", "", statement.text));
+                    for word in words {
+                        listing.push_str(&format!("{:08x}: {:08x}    {}\n", address, word, disassemble_instruction(*word)));
+                        address += 1;
+                    }
+                }
+            }
+        }
+        for (_, names) in labels {
+            for name in names {
+                listing.push_str(&format!("{name}:\n"));
+            }
+        }
+        listing
+    }
+}
+
+/// The instruction part of a source line, without its labels or comment.
+fn statement_text(text: &str) -> String {
+    let mut quote = None;
+    let mut end = text.len();
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(_), '\\') if !escaped => { escaped = true; continue }
+            (Some(q), c) if c == q && !escaped => { quote = None }
+            (None, '\'' | '"') => { quote = Some(c) }
+            (None, ';') => { end = i; break }
+            _ => {}
+        }
+        escaped = false;
+    }
+
+    let mut text = text[..end].trim();
+    loop {
+        let name_end = text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(text.len());
+        match text[name_end..].trim_start().strip_prefix(':') {
+            Some(rest) if name_end > 0 => { text = rest.trim_start() }
+            _ => { return String::from(text) }
+        }
+    }
+}
+
+/// Assembles `source` into a program image.
+///
+/// Every error found is returned, each with its line number.
+pub fn assemble(source: &str) -> Result<Assembly, Vec<AsmError>> {
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+    let mut errors = Vec::new();
+    let mut address = 0;
+    let mut registers = DEFAULT_PSEUDO_REGISTERS;
+
+    // First pass: parse every line and give each label its address.
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let tokens = match tokenize(text, line) {
+            Ok(tokens) => { tokens },
+            Err(e) => { errors.push(e); continue }
+        };
+
+        let mut rest = tokens.as_slice();
+        while let [Token::Ident(name), Token::Colon, tail @ ..] = rest {
+            if register(name).is_some() || name.starts_with('.') || opcode(name).is_some()
+                || PSEUDO_INSTRUCTIONS.contains(&name.as_str()) {
+                errors.push(AsmError { line, message: format!("`{name}` cannot be used as a label") });
+            }
+            else if labels.insert(name.clone(), address).is_some() {
+                errors.push(AsmError { line, message: format!("label `{name}` is defined twice") });
+            }
+            rest = tail;
+        }
+
+        let item = match rest {
+            [] => { continue }
+            // `.pseudo` takes no space, it only changes how the lines after
+            // it are expanded.
+            [Token::Ident(name), operands @ ..] if name == ".pseudo" => {
+                match parse_pseudo_registers(operands, line) {
+                    Ok(pseudo) => { registers = pseudo },
+                    Err(e) => { errors.push(e) }
+                }
+                continue
+            }
+            [Token::Ident(name), operands @ ..] if name.starts_with('.') => { parse_directive(name, operands, line) }
+            [Token::Ident(name), operands @ ..] => { parse_instruction(name, operands, address, registers, line) }
+            _ => { error(line, String::from("expected an instruction, directive or label")) }
+        };
+        match item {
+            Ok(item) => {
+                let size = item.size();
+                statements.push(Statement { line, address, text: statement_text(text), item });
+                address += size;
+            }
+            Err(e) => { errors.push(e) }
+        }
+    }
+
+    // Second pass: resolve labels and encode.
+    let mut words = Vec::with_capacity(address);
+    for statement in statements.iter() {
+        match encode(&statement.item, &labels, statement.line) {
+            Ok(encoded) => { words.extend(encoded) }
+            Err(e) => { errors.push(e) }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(Assembly { words, labels, statements })
+    }
+    else {
+        errors.sort_by_key(|e| e.line);
+        Err(errors)
+    }
+}
+
+fn evaluate(expr: &Expr, labels: &HashMap<String, usize>, line: usize) -> Result<i64, AsmError> {
+    let mut value: i64 = 0;
+    for (sign, term) in expr.terms.iter() {
+        let term = match term {
+            Term::Number(n) => { *n },
+            Term::Label(name) => {
+                *labels.get(name).ok_or(AsmError { line, message: format!("undefined label `{name}`") })? as i64
+            }
+        };
+        value = value.wrapping_add(sign * term);
+    }
+    if expr.part == Part::Whole {
+        return Ok(value);
+    }
+
+    let word = word_value(value, line)?;
+    match expr.part {
+        Part::High => { Ok((word >> 16) as i64) },
+        _ => { Ok((word & 0xffff) as i64) }
+    }
+}
+
+/// `value` as a 32-bit word. Negative values are stored in two's complement.
+fn word_value(value: i64, line: usize) -> Result<u32, AsmError> {
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return error(line, format!("{value} does not fit in a word"));
+    }
+    Ok(value as u32)
+}
+
+fn encode(item: &Item, labels: &HashMap<String, usize>, line: usize) -> Result<Vec<u32>, AsmError> {
+    match item {
+        Item::Zero(count) => { Ok(vec![0; *count]) }
+        Item::Words(exprs) => {
+            exprs.iter().map(|expr| word_value(evaluate(expr, labels, line)?, line)).collect()
+        }
+        Item::Expansion(items) => {
+            let mut words = Vec::new();
+            for item in items {
+                words.extend(encode(item, labels, line)?);
+            }
+            Ok(words)
+        }
+        Item::Instruction(op, operands) => {
+            let mut registers = Vec::new();
+            let mut value = None;
+            for operand in operands {
+                match operand {
+                    Operand::Register(r) => { registers.push(*r as u32) },
+                    Operand::Value(expr) => { value = Some(evaluate(expr, labels, line)?) }
+                }
+            }
+
+            let op_bits = (*op as u32) << 28;
+            match (op, registers.as_slice(), value) {
+                (CPU_Opcode::LV, [rl], Some(value)) => {
+                    if !(0..1 << 25).contains(&value) {
+                        return error(line, format!("movi value {value} does not fit in 25 bits"));
+                    }
+                    Ok(vec![op_bits | (rl << 25) | value as u32])
+                }
+                (CPU_Opcode::HALT, [], None) => { Ok(vec![op_bits]) }
+                (CPU_Opcode::UnmapSeg | CPU_Opcode::Out | CPU_Opcode::In, [rc], None) => {
+                    Ok(vec![op_bits | rc])
+                }
+                (CPU_Opcode::PortIn, [ra, rc], None) => {
+                    Ok(vec![op_bits | (ra << 6) | rc])
+                }
+                (CPU_Opcode::MapSeg | CPU_Opcode::LP | CPU_Opcode::PortOut, [rb, rc], None) => {
+                    Ok(vec![op_bits | (rb << 3) | rc])
+                }
+                (_, [ra, rb, rc], None) => {
+                    Ok(vec![op_bits | (ra << 6) | (rb << 3) | rc])
+                }
+                _ => { error(line, format!("wrong operands for {}", op.mnemonic())) }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::assembler::assemble;
+    use crate::cpu::{CPU, FaultKind, FaultPolicy};
+    use crate::disassembler::Disassembly;
+    use crate::harddrive::HardDrive;
+    use crate::machine::MachineBuilder;
+    use crate::MachinePart::MachinePart;
+    use crate::ram::RAM;
+    use crate::scheduler::UNLIMITED;
+
+    #[test]
+    fn assemble_test(){
+        let assembly = assemble("
+            ; print a character
+            start:  movi r1, 'H'
+                    output r1
+                    add r3, r2, r1
+                    map r2, r1
+                    halt
+            data:   .word 0x12345678, -1, start + 2
+                    .ascii \"hi\"
+                    .zero 2
+        ").unwrap();
+
+        assert_eq!(assembly.words, vec![
+            0xd2000048, 0xa0000001, 0x300000d1, 0x80000011, 0x70000000,
+            0x12345678, 0xffffffff, 2, 'h' as u32, 'i' as u32, 0, 0
+        ]);
+        assert_eq!(assembly.labels["data"], 5);
+    }
+
+    #[test]
+    fn disassembly_round_trip_test(){
+        let words = vec![0xde000003, 0xc0000007, 0x70000000, 0xd2000048, 0xa0000001, 0x70000000, 0xf0000000];
+        let source = Disassembly::new(words.clone()).source();
+
+        assert_eq!(assemble(&source).unwrap().words, words);
+    }
+
+    #[test]
+    fn errors_have_line_numbers_test(){
+        let errors = assemble("halt\nadd r1, r2\nmovi r1, 0x2000000\njump nowhere\nmovi r0, missing").err().unwrap();
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+
+        assert_eq!(lines, vec![2, 3, 4, 5]);
+        assert_eq!(errors[3].to_string(), "line 5: undefined label `missing`");
+    }
+
+    #[test]
+    fn pseudo_instructions_test(){
+        let assembly = assemble("
+                    li32 r1, 0x12345678
+                    movi r2, 100
+                    movi r3, 58
+                    sub r4, r2, r3
+                    xor r2, r2, r3
+                    shl r3, r3, 28
+                    jnz r0, fail
+                    jz r0, skip
+            fail:   movi r1, 0
+            skip:   call double
+                    halt
+            double: add r4, r4, r4
+                    ret
+        ").unwrap();
+
+        let mut machine = MachineBuilder::new()
+            .cpu(CPU::new(UNLIMITED, 32, 8))
+            .ram(RAM::new())
+            .drive(HardDrive::from_bytes(assembly.to_bytes()))
+            .um_mode(true)
+            .build()
+            .unwrap();
+        assert_eq!(machine.boot(), Ok(()));
+
+        let state = machine.part::<CPU>().unwrap().snapshot();
+        assert_eq!(state[1].1, 0x12345678);
+        assert_eq!(state[2].1, 100 ^ 58);
+        assert_eq!(state[3].1, 0xa0000000);
+        assert_eq!(state[4].1, 84);
+    }
+
+    #[test]
+    fn trap_handler_test(){
+        // The handler reads the trap frame in r5 to r7 with pseudo-instructions,
+        // so it moves their registers out of the way first.
+        let assembly = assemble("
+                    .pseudo r1, r2, r3
+                    movi r4, 9
+                    div r4, r4, r0
+                    halt
+            handler:
+                    shr r4, r7, 28      ; the faulting opcode
+                    sub r4, r4, r6      ; minus the faulting pc
+                    call done
+            done:   halt
+        ").unwrap();
+
+        let mut machine = MachineBuilder::new()
+            .cpu(CPU::new(UNLIMITED, 32, 8))
+            .ram(RAM::new())
+            .drive(HardDrive::from_bytes(assembly.to_bytes()))
+            .um_mode(true)
+            .build()
+            .unwrap();
+        let cpu = machine.part_mut::<CPU>().unwrap();
+        cpu.set_trap_vector(Some(assembly.labels["handler"] as u64));
+        cpu.set_fault_policy(FaultKind::DivideByZero, FaultPolicy::Trap);
+        assert_eq!(machine.boot(), Ok(()));
+
+        let registers = machine.part::<CPU>().unwrap().registers();
+        assert_eq!(registers[4], 5 - 1);
+        assert_eq!(registers[5], FaultKind::DivideByZero as u64);
+        assert_eq!(registers[6], 1);
+        assert_eq!(registers[7], assembly.words[1] as u64);
+        assert_eq!(registers[1], assembly.labels["done"] as u64);
+    }
+
+    #[test]
+    fn listing_shows_expansions_test(){
+        let listing = assemble("start: not r1, r2\n  halt ; done\n").unwrap().listing();
+        assert_eq!(listing, "start:\n                    ; not r1, r2\n00000000: 60000052    nand r1, r2, r2\n00000001: 70000000  halt\n");
+
+        let errors = assemble("sub r7, r1, r2").err().unwrap();
+        assert_eq!(errors[0].line, 1);
+        assert!(assemble(".pseudo r1, r2, r3\nsub r7, r6, r5").is_ok());
+        assert!(assemble(".pseudo r1, r2, r3\nsub r4, r2, r5").is_err());
+        assert_eq!(assemble("halt\n.pseudo r0, r1, r2").err().unwrap()[0].line, 2);
+    }
+}
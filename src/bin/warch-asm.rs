@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::Path;
+use clap::Parser;
+use warch::assembler::assemble;
+
+/// Assembles WARCH source into a program image.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args{
+    /// The assembler source file.
+    input: String,
+
+    /// Where to write the image. Defaults to the input with a .wmiso extension.
+    #[arg(short = 'o', long = "output")]
+    output: Option<String>,
+
+    /// Also print a listing of the assembled image, with pseudo-instructions expanded.
+    #[arg(short = 'l', long = "listing", required = false)]
+    listing: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let source = fs::read_to_string(&args.input).unwrap_or_else(|e| {
+        eprintln!("{}: {e}", args.input);
+        std::process::exit(1);
+    });
+
+    let assembly = assemble(&source).unwrap_or_else(|errors| {
+        for error in errors.iter() {
+            eprintln!("{}:{}: {}", args.input, error.line, error.message);
+        }
+        std::process::exit(1);
+    });
+
+    if args.listing {
+        print!("{}", assembly.listing());
+    }
+
+    let output = args.output.unwrap_or_else(|| {
+        Path::new(&args.input).with_extension("wmiso").display().to_string()
+    });
+    if let Err(e) = fs::write(&output, assembly.to_bytes()) {
+        eprintln!("{output}: {e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,124 @@
+use clap::Parser;
+use warch::disassembler::disassemble_instruction;
+use warch::trace::{SegmentChange, TraceReader, TraceRecord};
+
+/// Prints an execution trace recorded with `warch --trace`.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args{
+    /// The trace file.
+    input: String,
+
+    /// Only show instructions at this address (decimal or 0x hex).
+    #[arg(long = "pc", value_parser = parse_number)]
+    pc: Option<u64>,
+
+    /// Only show instructions with this mnemonic, e.g. "store".
+    #[arg(long = "op")]
+    op: Option<String>,
+
+    /// Only show instructions that changed this register, e.g. "r3".
+    #[arg(long = "reg")]
+    register: Option<String>,
+
+    /// Only show instructions that stored to memory.
+    #[arg(long = "writes", required = false)]
+    writes: bool,
+
+    /// Only show instructions that read or wrote a byte or read a port.
+    #[arg(long = "io", required = false)]
+    io: bool,
+
+    /// Skip this many instructions first.
+    #[arg(long = "skip", default_value_t = 0)]
+    skip: u64,
+
+    /// Stop after showing this many instructions.
+    #[arg(long = "limit")]
+    limit: Option<u64>,
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => { u64::from_str_radix(hex, 16) },
+        None => { text.parse::<u64>() }
+    };
+    parsed.map_err(|_| format!("`{text}` is not a number"))
+}
+
+fn matches(args: &Args, record: &TraceRecord) -> bool {
+    let text = disassemble_instruction(record.instruction);
+    let mnemonic = text.split_whitespace().next().unwrap_or("");
+    let register = args.register.as_deref()
+        .and_then(|r| r.strip_prefix('r'))
+        .and_then(|r| r.parse::<usize>().ok());
+
+    args.pc.is_none_or(|pc| record.pc == pc)
+        && args.op.as_deref().is_none_or(|op| mnemonic == op)
+        && (args.register.is_none() || record.registers.iter().any(|(r, _)| Some(*r) == register))
+        && (!args.writes || !record.writes.is_empty())
+        && (!args.io || !record.output.is_empty() || !record.input.is_empty() || !record.port_reads.is_empty())
+}
+
+/// One line per instruction: its number in the trace, address, raw word and
+/// disassembly, followed by everything it changed.
+fn format_record(number: u64, record: &TraceRecord) -> String {
+    let mut effects = Vec::new();
+    for (register, value) in record.registers.iter() {
+        effects.push(format!("r{register}={value:#x}"));
+    }
+    for (segment, index, value) in record.writes.iter() {
+        effects.push(format!("m[{segment}][{index}]={value:#x}"));
+    }
+    for change in record.segments.iter() {
+        match change {
+            SegmentChange::Map { segment, words } => { effects.push(format!("map m[{segment}]={words}")) },
+            SegmentChange::Unmap { segment } => { effects.push(format!("umap m[{segment}]")) },
+            SegmentChange::LoadProgram { segment } => { effects.push(format!("m[0]=m[{segment}]")) }
+        }
+    }
+    for byte in record.output.iter() {
+        effects.push(format!("out={:?}", *byte as char));
+    }
+    for byte in record.input.iter() {
+        match byte {
+            Some(byte) => { effects.push(format!("in={:?}", *byte as char)) },
+            None => { effects.push(String::from("in=EOF")) }
+        }
+    }
+    for (port, value) in record.port_reads.iter() {
+        effects.push(format!("port[{port:#x}]={value:#x}"));
+    }
+    if let Some(code) = record.fault {
+        effects.push(format!("fault={code}"));
+    }
+
+    let line = format!("{:>10}  {:08x}: {:08x}  {:<24}{}", number, record.pc, record.instruction,
+                       disassemble_instruction(record.instruction), effects.join(" "));
+    String::from(line.trim_end())
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let reader = TraceReader::open(&args.input).unwrap_or_else(|e| {
+        eprintln!("{}: {e}", args.input);
+        std::process::exit(1);
+    });
+    println!("; {}-bit registers, starting at {:x?}", reader.header().register_width, reader.header().registers);
+
+    let mut shown = 0;
+    for (number, record) in reader.enumerate().skip(args.skip as usize) {
+        let record = record.unwrap_or_else(|e| {
+            eprintln!("{}: {e}", args.input);
+            std::process::exit(1);
+        });
+        if args.limit == Some(shown) {
+            break;
+        }
+        if matches(&args, &record) {
+            println!("{}", format_record(number as u64, &record));
+            shown += 1;
+        }
+    }
+}
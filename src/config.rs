@@ -0,0 +1,265 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use serde::Deserialize;
+use crate::gpu::MAX_RESOLUTION;
+
+/// The description of a machine, usually read from a TOML file.
+///
+/// Every section is optional and defaults to the stock WARCH machine:
+///
+/// ```toml
+/// [cpu]
+/// clock_speed = 4770000   # hertz, 0 for unlimited
+/// register_width = 32     # 8, 16, 32 or 64 bits
+/// register_count = 16
+///
+/// [ram]
+/// capacity = 1048576      # words
+/// max_segments = 4096     # optional
+///
+/// [gpu]
+/// clock_speed = 1000000
+/// register_width = 32
+/// register_count = 8
+/// width = 100
+/// height = 100
+///
+/// [screen]
+/// pixel_width = 8
+/// pixel_height = 6
+///
+/// [[drive]]
+/// image = "maindisk.wmiso"
+/// ```
+///
+/// The first drive is the one the machine boots from.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    pub cpu: CpuConfig,
+    pub ram: RamConfig,
+    pub gpu: GpuConfig,
+    pub screen: ScreenConfig,
+    #[serde(rename = "drive")]
+    pub drives: Vec<DriveConfig>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CpuConfig {
+    pub clock_speed: u64,
+    pub register_width: usize,
+    pub register_count: usize
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RamConfig {
+    pub capacity: usize,
+    pub max_segments: Option<usize>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpuConfig {
+    pub clock_speed: u64,
+    pub register_width: usize,
+    pub register_count: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScreenConfig {
+    pub pixel_width: u32,
+    pub pixel_height: u32
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DriveConfig {
+    pub image: String
+}
+
+/// An error reading or validating a `MachineConfig`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io { path: String, error: std::io::Error },
+    /// The file is not valid TOML or has unknown or mistyped keys.
+    Parse(toml::de::Error),
+    /// `key` has a value the machine cannot be built with.
+    Invalid { key: String, reason: String }
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        CpuConfig {
+            clock_speed: 4_770_000, // the 8088
+            register_width: 32,
+            register_count: 16
+        }
+    }
+}
+
+impl Default for RamConfig {
+    fn default() -> Self {
+        RamConfig {
+            capacity: 1 << 20,
+            max_segments: None
+        }
+    }
+}
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        GpuConfig {
+            clock_speed: 1_000_000,
+            register_width: 32,
+            register_count: 8,
+            width: 100,
+            height: 100
+        }
+    }
+}
+
+impl Default for ScreenConfig {
+    fn default() -> Self {
+        ScreenConfig {
+            pixel_width: 8,
+            pixel_height: 6
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self{
+            ConfigError::Io { path, error } => {
+                write!(f, "could not read {}: {}", path, error)
+            }
+            ConfigError::Parse(error) => {
+                write!(f, "{}", error)
+            }
+            ConfigError::Invalid { key, reason } => {
+                write!(f, "invalid value for `{}`: {}", key, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(key: &str, reason: &str) -> ConfigError {
+    ConfigError::Invalid {
+        key: String::from(key),
+        reason: String::from(reason)
+    }
+}
+
+impl MachineConfig {
+    /// Reads and validates a configuration file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(&path).map_err(|error| ConfigError::Io {
+            path: path.as_ref().display().to_string(),
+            error
+        })?;
+        Self::from_toml(&text)
+    }
+
+    /// Parses and validates a configuration.
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        let config: MachineConfig = toml::from_str(text).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks every value the parts would otherwise reject or panic on.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if ![8, 16, 32, 64].contains(&self.cpu.register_width) {
+            return Err(invalid("cpu.register_width", "must be 8, 16, 32 or 64"));
+        }
+        // Instructions address r[0] to r[7].
+        if self.cpu.register_count < 8 {
+            return Err(invalid("cpu.register_count", "must be at least 8"));
+        }
+        if self.ram.capacity == 0 {
+            return Err(invalid("ram.capacity", "must be greater than 0"));
+        }
+        if self.ram.max_segments == Some(0) {
+            return Err(invalid("ram.max_segments", "must be greater than 0"));
+        }
+        if self.gpu.clock_speed == 0 {
+            return Err(invalid("gpu.clock_speed", "must be greater than 0"));
+        }
+        if ![8, 16, 32, 64].contains(&self.gpu.register_width) {
+            return Err(invalid("gpu.register_width", "must be 8, 16, 32 or 64"));
+        }
+        if self.gpu.register_count < 8 {
+            return Err(invalid("gpu.register_count", "must be at least 8"));
+        }
+        if !(1..=MAX_RESOLUTION).contains(&self.gpu.width) {
+            return Err(invalid("gpu.width", &format!("must be between 1 and {MAX_RESOLUTION}")));
+        }
+        if !(1..=MAX_RESOLUTION).contains(&self.gpu.height) {
+            return Err(invalid("gpu.height", &format!("must be between 1 and {MAX_RESOLUTION}")));
+        }
+        if self.screen.pixel_width == 0 {
+            return Err(invalid("screen.pixel_width", "must be greater than 0"));
+        }
+        if self.screen.pixel_height == 0 {
+            return Err(invalid("screen.pixel_height", "must be greater than 0"));
+        }
+        for (i, drive) in self.drives.iter().enumerate() {
+            if !Path::new(&drive.image).is_file() {
+                return Err(invalid(&format!("drive[{i}].image"), &format!("{} is not a file", drive.image)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::config::{ConfigError, MachineConfig};
+
+    #[test]
+    fn defaults_test(){
+        let config = MachineConfig::from_toml("").unwrap();
+        assert_eq!(config.cpu.register_width, 32);
+        assert_eq!(config.gpu.width, 100);
+        assert!(config.drives.is_empty());
+    }
+
+    #[test]
+    fn invalid_value_names_key_test(){
+        let error = MachineConfig::from_toml("[cpu]\nregister_width = 12\n").unwrap_err();
+        match error {
+            ConfigError::Invalid { key, .. } => { assert_eq!(key, "cpu.register_width") }
+            _ => { panic!("expected an invalid value error") }
+        }
+    }
+
+    fn invalid_key(text: &str) -> String {
+        match MachineConfig::from_toml(text) {
+            Err(ConfigError::Invalid { key, .. }) => { key }
+            _ => { panic!("expected an invalid value error") }
+        }
+    }
+
+    #[test]
+    fn gpu_size_test(){
+        assert_eq!(invalid_key("[gpu]\nwidth = 4000\nheight = 4000\n"), "gpu.width");
+        assert_eq!(invalid_key("[gpu]\nheight = 1025\n"), "gpu.height");
+        assert_eq!(invalid_key("[gpu]\nwidth = 0\n"), "gpu.width");
+        assert!(MachineConfig::from_toml("[gpu]\nwidth = 1024\nheight = 1024\n").is_ok());
+    }
+
+    #[test]
+    fn unknown_key_names_key_test(){
+        let error = MachineConfig::from_toml("[cpu]\nclok_speed = 12\n").unwrap_err();
+        assert!(error.to_string().contains("clok_speed"));
+    }
+}
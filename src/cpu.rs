@@ -1,22 +1,186 @@
-use std::io::{Read, stdin};
-use std::thread::sleep;
-use std::time::Duration;
+use std::io::{Read, stdin, stdout, Write};
 use crossbeam::channel::Sender;
-use crate::machine::{Machine, MachineWrapper, VideoOutWrapper};
-use crate::ram::RAM;
+use std::any::Any;
+use crate::machine::VideoOutWrapper;
+use crate::MachinePart::{Bus, MachinePart, StateValue};
+use crate::disassembler::disassemble_instruction;
+use crate::ram::{MemoryError, RAM};
+use crate::scheduler::{Scheduler, SchedulerReport};
+use crate::trace::TraceWriter;
+use std::collections::VecDeque;
 
 pub struct CPU{
-    clock_speed: u64, // hertz
+    clock_speed: u64, // hertz, or scheduler::UNLIMITED
     register_width: usize,
     registers: Vec<u64>,
     program_counter: u64,
     stack: Vec<u64>,
     halt_flag: bool,
-    senders: Vec<Sender<VideoOutWrapper>>
+    senders: Vec<Sender<VideoOutWrapper>>,
+    fault_policies: [FaultPolicy; FAULT_KINDS],
+    trap_vector: Option<u64>,
+    trap_frame: Option<TrapFrame>,
+    report: Option<SchedulerReport>,
+    cycle_costs: CycleCosts,
+    cycles: u64,
+    tracer: Option<TraceWriter>,
+    replay: Option<VecDeque<Option<u8>>>, // input bytes fed to `input` instead of stdin
+    replay_ports: Option<VecDeque<u64>>, // values fed to `portin` instead of reading the bus
+    output_port: Option<u64>
+}
+
+/// Register that receives the fault code when entering the trap handler.
+pub const TRAP_CODE_REGISTER: usize = 5;
+/// Register that receives the program counter of the faulting instruction.
+pub const TRAP_PC_REGISTER: usize = 6;
+/// Register that receives the faulting instruction word.
+pub const TRAP_INSTRUCTION_REGISTER: usize = 7;
+
+const FAULT_KINDS: usize = 7;
+
+/// A fault raised by the CPU while executing an instruction.
+///
+/// Every variant carries the program counter of the faulting instruction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CpuFault {
+    InvalidOpcode { pc: u64, instruction: u32 },
+    DivideByZero { pc: u64 },
+    BadSegment { pc: u64, segment: u64 },
+    OutOfBounds { pc: u64, segment: u64, index: u64 },
+    OutputRange { pc: u64, value: u64 },
+    OutOfMemory { pc: u64, requested: u64 },
+    /// Every segment id the RAM's segment limit allows is already mapped.
+    TooManySegments { pc: u64, limit: u64 }
+}
+
+/// The kind of a `CpuFault`, used to choose the `FaultPolicy` applied to it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultKind { InvalidOpcode, DivideByZero, BadSegment, OutOfBounds, OutputRange, OutOfMemory, TooManySegments }
+
+/// What the CPU does when a fault of a given kind is raised.
+///
+/// * `Halt`: stop the machine and print a diagnostic.
+/// * `Trap`: jump to the trap vector with the fault code, faulting PC and
+///   instruction placed in `TRAP_CODE_REGISTER`, `TRAP_PC_REGISTER` and
+///   `TRAP_INSTRUCTION_REGISTER`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultPolicy { Halt, Trap }
+
+/// The state saved when the CPU enters its trap handler.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrapFrame {
+    pub fault: CpuFault,
+    pub pc: u64,
+    pub instruction: u32
+}
+
+impl CpuFault {
+    pub fn kind(&self) -> FaultKind {
+        match self{
+            CpuFault::InvalidOpcode { .. } => { FaultKind::InvalidOpcode },
+            CpuFault::DivideByZero { .. } => { FaultKind::DivideByZero },
+            CpuFault::BadSegment { .. } => { FaultKind::BadSegment },
+            CpuFault::OutOfBounds { .. } => { FaultKind::OutOfBounds },
+            CpuFault::OutputRange { .. } => { FaultKind::OutputRange },
+            CpuFault::OutOfMemory { .. } => { FaultKind::OutOfMemory },
+            CpuFault::TooManySegments { .. } => { FaultKind::TooManySegments }
+        }
+    }
+
+    pub fn pc(&self) -> u64 {
+        match *self{
+            CpuFault::InvalidOpcode { pc, .. } |
+            CpuFault::DivideByZero { pc } |
+            CpuFault::BadSegment { pc, .. } |
+            CpuFault::OutOfBounds { pc, .. } |
+            CpuFault::OutputRange { pc, .. } |
+            CpuFault::OutOfMemory { pc, .. } |
+            CpuFault::TooManySegments { pc, .. } => { pc }
+        }
+    }
+
+    /// Converts a `MemoryError` raised by the instruction at `pc` into a fault.
+    pub fn from_memory_error(error: MemoryError, pc: u64) -> Self {
+        match error{
+            MemoryError::UnmappedSegment { segment } |
+            MemoryError::SegmentFreed { segment } |
+            MemoryError::DoubleFree { segment } |
+            MemoryError::ReservedSegment { segment } => {
+                CpuFault::BadSegment{ pc, segment: segment as u64 }
+            }
+            MemoryError::IndexOutOfRange { segment, index, .. } => {
+                CpuFault::OutOfBounds{ pc, segment: segment as u64, index: index as u64 }
+            }
+            MemoryError::OutOfMemory { requested, .. } => {
+                CpuFault::OutOfMemory{ pc, requested: requested as u64 }
+            }
+            MemoryError::TooManySegments { limit } => {
+                CpuFault::TooManySegments{ pc, limit: limit as u64 }
+            }
+        }
+    }
+
+    /// The code handed to the guest in `TRAP_CODE_REGISTER`.
+    pub fn code(&self) -> u64 {
+        self.kind() as u64
+    }
+}
+
+impl std::fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            CpuFault::InvalidOpcode { pc, instruction } => {
+                write!(f, "invalid opcode in instruction {:#010x} at pc {}", instruction, pc)
+            }
+            CpuFault::DivideByZero { pc } => {
+                write!(f, "division by zero at pc {}", pc)
+            }
+            CpuFault::BadSegment { pc, segment } => {
+                write!(f, "bad segment {} at pc {}", segment, pc)
+            }
+            CpuFault::OutOfBounds { pc, segment, index } => {
+                write!(f, "index {} out of bounds for segment {} at pc {}", index, segment, pc)
+            }
+            CpuFault::OutputRange { pc, value } => {
+                write!(f, "output value {} out of range 0..=255 at pc {}", value, pc)
+            }
+            CpuFault::OutOfMemory { pc, requested } => {
+                write!(f, "out of memory mapping {} words at pc {}", requested, pc)
+            }
+            CpuFault::TooManySegments { pc, limit } => {
+                write!(f, "all {} segments are mapped at pc {}", limit, pc)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CPU_Opcode { CMov, Load, Store, Add, Mul, Div, NAND, HALT, MapSeg, UnmapSeg, Out, In, LP, LV, PortIn, PortOut, INVALID }
+impl CPU_Opcode {
+    /// The assembler mnemonic, as listed in the README.
+    pub fn mnemonic(&self) -> &'static str {
+        match self{
+            CPU_Opcode::CMov => { "cmov" },
+            CPU_Opcode::Load => { "load" },
+            CPU_Opcode::Store => { "store" },
+            CPU_Opcode::Add => { "add" },
+            CPU_Opcode::Mul => { "mul" },
+            CPU_Opcode::Div => { "div" },
+            CPU_Opcode::NAND => { "nand" },
+            CPU_Opcode::HALT => { "halt" },
+            CPU_Opcode::MapSeg => { "map" },
+            CPU_Opcode::UnmapSeg => { "umap" },
+            CPU_Opcode::Out => { "output" },
+            CPU_Opcode::In => { "input" },
+            CPU_Opcode::LP => { "run" },
+            CPU_Opcode::LV => { "movi" },
+            CPU_Opcode::PortIn => { "portin" },
+            CPU_Opcode::PortOut => { "portout" },
+            CPU_Opcode::INVALID => { "invalid" }
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum CPU_Opcode { CMov, Load, Store, Add, Mul, Div, NAND, HALT, MapSeg, UnmapSeg, Out, In, LP, LV, INVALID }
 pub fn get_opcode(code: u32) -> CPU_Opcode {
     match code{
         0 => { CPU_Opcode::CMov },
@@ -33,6 +197,8 @@ pub fn get_opcode(code: u32) -> CPU_Opcode {
         11=> { CPU_Opcode::In },
         12 => { CPU_Opcode::LP },
         13 => { CPU_Opcode::LV },
+        14 => { CPU_Opcode::PortIn },
+        15 => { CPU_Opcode::PortOut },
         _ => {
             CPU_Opcode::INVALID
         }
@@ -40,6 +206,9 @@ pub fn get_opcode(code: u32) -> CPU_Opcode {
 }
 
 pub fn mask(width: u32) -> u64{
+    if width >= 64 {
+        return u64::MAX;
+    }
     (1 << width) - 1
 }
 
@@ -52,8 +221,85 @@ pub fn check_fits(val: u32, bits: u32) -> bool {
     val < 2_u32.pow(bits)
 }
 
+/// The number of cycles each instruction takes.
+///
+/// An instruction costs its opcode's base cycles plus, for `map`, `umap`
+/// and `run`, its per-word cycles for every word of the segment it maps,
+/// unmaps or loads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleCosts {
+    base: [u64; OPCODES],
+    per_word: [u64; OPCODES]
+}
+
+const OPCODES: usize = CPU_Opcode::INVALID as usize;
+
+impl CycleCosts {
+    /// Every instruction takes `cycles` cycles, regardless of segment size.
+    pub fn uniform(cycles: u64) -> Self {
+        CycleCosts {
+            base: [cycles; OPCODES],
+            per_word: [0; OPCODES]
+        }
+    }
+    
+    pub fn set(&mut self, op: CPU_Opcode, cycles: u64) {
+        if let Some(base) = self.base.get_mut(op as usize) {
+            *base = cycles;
+        }
+    }
+    
+    pub fn set_per_word(&mut self, op: CPU_Opcode, cycles: u64) {
+        if let Some(per_word) = self.per_word.get_mut(op as usize) {
+            *per_word = cycles;
+        }
+    }
+    
+    /// The cycles taken by `op` touching a segment of `words` words.
+    pub fn cost(&self, op: CPU_Opcode, words: usize) -> u64 {
+        match (self.base.get(op as usize), self.per_word.get(op as usize)) {
+            (Some(base), Some(per_word)) => { base + per_word * words as u64 },
+            _ => { 0 }
+        }
+    }
+}
+
+impl Default for CycleCosts {
+    /// Rough costs for a simple microcoded CPU: register operations are
+    /// cheap, memory and multiplication cost more, division is slow, and
+    /// mapping or loading a segment has to clear or copy every word.
+    fn default() -> Self {
+        let mut costs = CycleCosts::uniform(1);
+        costs.set(CPU_Opcode::Load, 2);
+        costs.set(CPU_Opcode::Store, 2);
+        costs.set(CPU_Opcode::Mul, 4);
+        costs.set(CPU_Opcode::Div, 16);
+        costs.set(CPU_Opcode::MapSeg, 8);
+        costs.set(CPU_Opcode::UnmapSeg, 8);
+        costs.set(CPU_Opcode::Out, 4);
+        costs.set(CPU_Opcode::In, 4);
+        costs.set(CPU_Opcode::LP, 4);
+        costs.set(CPU_Opcode::PortIn, 4);
+        costs.set(CPU_Opcode::PortOut, 4);
+        costs.set_per_word(CPU_Opcode::MapSeg, 1);
+        costs.set_per_word(CPU_Opcode::LP, 1);
+        costs
+    }
+}
+
 impl CPU{
+    /// Creates a CPU running at `clock_speed` hertz whose registers are
+    /// `register_width` bits wide.
+    ///
+    /// Only 8, 16, 32 and 64 bit registers are supported.
     pub fn new(clock_speed: u64, register_width: usize, register_count: usize) -> Self{
+        if ![8, 16, 32, 64].contains(&register_width) {
+            panic!("Unsupported register width {register_width}!");
+        }
+        // Instructions name registers with 3 bits, and traps use r5 to r7.
+        if register_count < 8 {
+            panic!("A CPU needs at least 8 registers, not {register_count}!");
+        }
         let registers = vec![0u64; register_count];
         let stack: Vec<u64> = Vec::new();
 
@@ -64,53 +310,234 @@ impl CPU{
             stack,
             program_counter: 0,
             halt_flag: false,
-            senders: Vec::new()
+            senders: Vec::new(),
+            fault_policies: [FaultPolicy::Halt; FAULT_KINDS],
+            trap_vector: None,
+            trap_frame: None,
+            report: None,
+            cycle_costs: CycleCosts::default(),
+            cycles: 0,
+            tracer: None,
+            replay: None,
+            replay_ports: None,
+            output_port: None
+        }
+    }
+
+    /// The mask every register value is wrapped to.
+    pub fn word_mask(&self) -> u64 {
+        mask(self.register_width as u32)
+    }
+
+    /// Sets the policy applied when a fault of the given kind is raised.
+    pub fn set_fault_policy(&mut self, kind: FaultKind, policy: FaultPolicy) {
+        self.fault_policies[kind as usize] = policy;
+    }
+
+    pub fn fault_policy(&self, kind: FaultKind) -> FaultPolicy {
+        self.fault_policies[kind as usize]
+    }
+
+    /// Sets the address in `m[0]` the CPU jumps to when a fault is trapped.
+    /// Without a trap vector every fault halts the machine.
+    pub fn set_trap_vector(&mut self, address: Option<u64>) {
+        self.trap_vector = address;
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.program_counter
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.program_counter = pc;
+    }
+
+    pub fn register_width(&self) -> usize {
+        self.register_width
+    }
+
+    pub fn registers(&self) -> &[u64] {
+        &self.registers
+    }
+
+    /// Sets register `index`, wrapped to the register width. Returns false
+    /// if there is no such register.
+    pub fn set_register(&mut self, index: usize, value: u64) -> bool {
+        let mask = self.word_mask();
+        match self.registers.get_mut(index) {
+            Some(register) => { *register = value & mask; true }
+            None => { false }
         }
     }
+
+    pub fn is_halted(&self) -> bool {
+        self.halt_flag
+    }
+
+    /// The number of cycles executed since the CPU was created.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    
+    pub fn cycle_costs(&self) -> &CycleCosts {
+        &self.cycle_costs
+    }
+    
+    pub fn set_cycle_costs(&mut self, costs: CycleCosts) {
+        self.cycle_costs = costs;
+    }
+
+    /// Records every instruction executed from now on, or stops recording
+    /// if `tracer` is `None`.
+    pub fn set_tracer(&mut self, tracer: Option<TraceWriter>) {
+        self.tracer = tracer;
+    }
+    
+    /// Stops recording and returns the tracer so it can be finished.
+    pub fn take_tracer(&mut self) -> Option<TraceWriter> {
+        self.tracer.take()
+    }
+    
+    /// Feeds `input` the given bytes, as recorded in a trace, instead of
+    /// reading stdin. `None` is the end of input, which is also what
+    /// `input` reads once the bytes run out.
+    pub fn replay_input(&mut self, input: Vec<Option<u8>>) {
+        self.replay = Some(input.into());
+    }
+
+    /// Feeds `portin` the given values, as recorded in a trace, instead of
+    /// reading the bus. Once they run out `portin` reads the bus again.
+    pub fn replay_port_reads(&mut self, values: Vec<u64>) {
+        self.replay_ports = Some(values.into());
+    }
+
+    /// Sends the bytes written by `output` to I/O port `port` instead of
+    /// stdout, e.g. `gpu::PORT_CONSOLE` to show them on the screen.
+    pub fn set_output_port(&mut self, port: Option<u64>) {
+        self.output_port = port;
+    }
+    
+    /// Speed statistics of the last call to `run`.
+    pub fn report(&self) -> Option<SchedulerReport> {
+        self.report
+    }
+
+    /// The state saved by the most recent trap, if any.
+    pub fn trap_frame(&self) -> Option<TrapFrame> {
+        self.trap_frame
+    }
     
     pub fn add_signaler(&mut self, sender: Sender<VideoOutWrapper>) {
         self.senders.push(sender);
     }
     
-    pub unsafe fn run(&mut self, mut machine: &MachineWrapper){
-        
-        let ram = (machine.ram);
-        
-        let delta_max = 1000000000_u128 / (self.clock_speed as u128);
+    /// Runs the program in `m[0]` until it halts.
+    ///
+    /// Instructions are throttled to `clock_speed` by a `Scheduler`, or run
+    /// as fast as possible if the clock speed is `scheduler::UNLIMITED`.
+    ///
+    /// Faults are handled according to their `FaultPolicy`; a fault that
+    /// halts the machine is returned as the error.
+    pub fn run(&mut self, ram: &mut RAM) -> Result<(), CpuFault>{
+        self.run_with(ram, |_| {})
+    }
+    
+    /// Same as `run`, but calls `tick` with the cycles taken by every
+    /// instruction so other parts can be clocked alongside the CPU.
+    pub fn run_with<F: FnMut(u64)>(&mut self, ram: &mut RAM, tick: F) -> Result<(), CpuFault>{
+        self.run_on(ram, &mut Ticker(tick))
+    }
+    
+    /// Same as `run`, with `portin` and `portout` reaching the ports on
+    /// `bus`, which is ticked with the cycles taken by every instruction.
+    pub fn run_on(&mut self, ram: &mut RAM, bus: &mut dyn Bus) -> Result<(), CpuFault>{
         
-        let mut clock = quanta::Clock::new();
-        let mut timer = clock.raw();
+        let mut scheduler = Scheduler::new(self.clock_speed);
         
         self.program_counter = 0;
-        'run: loop{
-            let delta = clock.raw() - timer;
+        let result = 'run: loop{
+            let cycles = self.cycles;
+            let result = self.step_on(ram, bus);
+            //self.print_state();
             
-            if delta > delta_max as u64 {
-                //println!("{delta}");
-                //println!("{}", clock.elapsed().as_nanos());
-                let instruction = (*machine.ram).get(0, self.program_counter as usize) as u32;
-
+            scheduler.account(self.cycles - cycles);
+            bus.tick(self.cycles - cycles);
+            
+            if result.is_err() || self.halt_flag {
+                break 'run result
+            }
+        };
+        
+        self.report = Some(scheduler.report());
+        stdout().flush().unwrap();
+        result
+    }
+    
+    /// Fetches and executes the instruction at the program counter.
+    ///
+    /// Faults are handled according to their `FaultPolicy`; a fault that
+    /// halts the machine is returned as the error.
+    pub fn step(&mut self, ram: &mut RAM) -> Result<(), CpuFault>{
+        self.step_on(ram, &mut ())
+    }
+    
+    /// Same as `step`, with `portin` and `portout` reaching the ports on `bus`.
+    pub fn step_on(&mut self, ram: &mut RAM, bus: &mut dyn Bus) -> Result<(), CpuFault>{
+        let result = match ram.try_get(0, self.program_counter as usize) {
+            Ok(word) => {
+                let instruction = word as u32;
                 //println!("{:b}", instruction);
-                self.compute(ram, instruction);
-                //self.print_state();
-
-                if self.halt_flag {
-                    break 'run
+                self.compute_on(ram, bus, instruction).map_err(|fault| (fault, instruction))
+            }
+            Err(error) => {
+                let fault = CpuFault::from_memory_error(error, self.program_counter);
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.step(self.program_counter, 0);
+                    tracer.fault(fault.code());
                 }
-                
-                // for sender in self.senders.iter(){
-                //     let c = (*machine.ram).to_vec(1);
-                //     sender.send(VideoOutWrapper{data: c}).unwrap();
-                // }
-                
-                timer = clock.raw();
+                Err((fault, 0))
             }
-            
+        };
+        
+        match result {
+            Ok(()) => { Ok(()) }
+            Err((fault, instruction)) => { self.handle_fault(fault, instruction) }
+        }
+    }
+    
+    /// Applies the configured `FaultPolicy` to a fault raised by `instruction`.
+    ///
+    /// Returns the fault back if it halted the machine.
+    fn handle_fault(&mut self, fault: CpuFault, instruction: u32) -> Result<(), CpuFault>{
+        let vector = match (self.fault_policy(fault.kind()), self.trap_vector) {
+            (FaultPolicy::Trap, Some(vector)) if vector != fault.pc() => { vector },
+            _ => {
+                eprintln!("CPU fault: {}", fault);
+                self.halt();
+                return Err(fault);
+            }
+        };
+        
+        self.trap_frame = Some(TrapFrame{
+            fault,
+            pc: fault.pc(),
+            instruction
+        });
+        self.load_val(TRAP_CODE_REGISTER, fault.code());
+        self.load_val(TRAP_PC_REGISTER, fault.pc());
+        self.load_val(TRAP_INSTRUCTION_REGISTER, instruction as u64);
+        self.program_counter = vector;
+        
+        // The trap registers belong to the faulting instruction's record.
+        if let Some(tracer) = &mut self.tracer {
+            tracer.registers(&self.registers);
         }
+        
+        Ok(())
     }
     
     pub fn build_instruction(&self, op: CPU_Opcode, ra: usize, rb: usize, rc: usize) -> u32{
-        if op as u32 > CPU_Opcode::LV as u32 ||
+        if op as u32 >= CPU_Opcode::INVALID as u32 ||
             ra >= self.registers.len() ||
             rb >= self.registers.len() ||
             rc >= self.registers.len()
@@ -120,9 +547,9 @@ impl CPU{
         ((op as u32) << 28) | (ra << 6) as u32 | (rb << 3) as u32 | rc as u32
     }
     
-    pub unsafe fn instruction(&mut self, machine: &MachineWrapper, op: CPU_Opcode, ra: usize, rb: usize, rc: usize){
+    pub fn instruction(&mut self, ram: &mut RAM, op: CPU_Opcode, ra: usize, rb: usize, rc: usize) -> Result<(), CpuFault>{
         let inst = self.build_instruction(op, ra, rb, rc);
-        self.compute(machine.ram, inst);
+        self.compute(ram, inst)
     }
     
     pub fn build_lv_inst(&self, rl: usize, lv: u32) -> u32{
@@ -132,34 +559,41 @@ impl CPU{
         ((CPU_Opcode::LV as u32) << 28) | (rl << 25) as u32 | (lv) as u32
     }
     
-    pub unsafe fn lv_instruction(&mut self, machine: &MachineWrapper, rl: usize, lv: u32){
-        println!("{lv}");
+    pub fn lv_instruction(&mut self, ram: &mut RAM, rl: usize, lv: u32) -> Result<(), CpuFault>{
         let inst = self.build_lv_inst(rl, lv);
-        self.compute(machine.ram, inst);
+        self.compute(ram, inst)
     }
     
+    /// Disassembles `instruction` into assembler syntax.
     pub fn disassemble(&self, instruction: u32) -> String {
-        let op = get_bits(instruction, 4, 28);
-        let ra: usize = get_bits(instruction, 3, 6) as usize;
-        let rb: usize = get_bits(instruction, 3, 3) as usize;
-        let rc: usize = get_bits(instruction, 3, 0) as usize;
-        let rl: usize = get_bits(instruction, 3, 25) as usize;
-        let lval = get_bits(instruction, 25, 0);
+        disassemble_instruction(instruction)
+    }
+    
+    /// Executes a single instruction and advances the program counter.
+    ///
+    /// On a fault the program counter is left pointing at the faulting
+    /// instruction.
+    pub fn compute(&mut self, ram: &mut RAM, instruction: u32) -> Result<(), CpuFault>{
+        self.compute_on(ram, &mut (), instruction)
+    }
+    
+    fn compute_on(&mut self, ram: &mut RAM, bus: &mut dyn Bus, instruction: u32) -> Result<(), CpuFault>{
+        if let Some(tracer) = &mut self.tracer {
+            tracer.step(self.program_counter, instruction);
+        }
         
-        //println!("{:x}", instruction);
+        let result = self.execute(ram, bus, instruction);
         
-        if op == CPU_Opcode::LV as u32 {
-            format!("{:?} {} {}", get_opcode(op), rl, lval)
-        }
-        else if op != CPU_Opcode::INVALID as u32{
-            format!("{:?} {} {} {}", get_opcode(op), ra, rb, rc)
-        }
-        else{
-            format!("Junk or invalid operation.")
+        if let Some(tracer) = &mut self.tracer {
+            tracer.registers(&self.registers);
+            if let Err(fault) = result {
+                tracer.fault(fault.code());
+            }
         }
+        result
     }
     
-    pub unsafe fn compute(&mut self, ram: *mut RAM, instruction: u32){
+    fn execute(&mut self, ram: &mut RAM, bus: &mut dyn Bus, instruction: u32) -> Result<(), CpuFault>{
         let op = get_bits(instruction, 4, 28);
         let ra: usize = get_bits(instruction, 3, 6) as usize;
         let rb: usize = get_bits(instruction, 3, 3) as usize;
@@ -167,16 +601,20 @@ impl CPU{
         let rl: usize = get_bits(instruction, 3, 25) as usize;
         let lval = get_bits(instruction, 25, 0);
         
+        // Segment words touched by map, umap and run, for the cycle cost.
+        let mut words = 0;
+        let mut jumped = false;
+        
         match op{
             opcode =>{
                 if opcode == CPU_Opcode::CMov as u32{
                     self.cmov(ra, rb, rc);
                 }
                 else if opcode == CPU_Opcode::Load as u32{
-                    self.load(ram, ra, rb, rc);
+                    self.load(ram, ra, rb, rc)?;
                 }
                 else if opcode == CPU_Opcode::Store as u32{
-                    self.store(ram, ra, rb, rc);
+                    self.store(ram, ra, rb, rc)?;
                 }
                 else if opcode == CPU_Opcode::Add as u32{
                     self.add(ra, rb, rc);
@@ -185,7 +623,7 @@ impl CPU{
                     self.mul(ra, rb, rc);
                 }
                 else if opcode == CPU_Opcode::Div as u32{
-                    self.div(ra, rb, rc);
+                    self.div(ra, rb, rc)?;
                 }
                 else if opcode == CPU_Opcode::NAND as u32{
                     self.nand(ra, rb, rc);
@@ -194,29 +632,44 @@ impl CPU{
                     self.halt();
                 }
                 else if opcode == CPU_Opcode::MapSeg as u32{
-                    self.map_seg(ram, rb, rc);
+                    words = self.map_seg(ram, rb, rc)?;
                 }
                 else if opcode == CPU_Opcode::UnmapSeg as u32{
-                    self.unmap_seg(ram, rc);
+                    words = self.unmap_seg(ram, rc)?;
                 }
                 else if opcode == CPU_Opcode::Out as u32{
-                    self.out(rc);
+                    self.out(bus, rc)?;
                 }
                 else if opcode == CPU_Opcode::In as u32{
                     self.await_in(rc);
                 }
                 else if opcode == CPU_Opcode::LP as u32{
-                    self.load_program(ram, rb, rc);
+                    // load_program sets the program counter itself.
+                    words = self.load_program(ram, rb, rc)?;
+                    jumped = true;
                 }
                 else if opcode == CPU_Opcode::LV as u32{
-                    self.load_val(rl, lval as u32);
+                    self.load_val(rl, lval as u64);
+                }
+                else if opcode == CPU_Opcode::PortIn as u32{
+                    self.port_in(bus, ra, rc);
+                }
+                else if opcode == CPU_Opcode::PortOut as u32{
+                    self.port_out(bus, rb, rc);
                 }
                 else{
-                    panic!("Bad Opcode! No operation found!");
+                    return Err(CpuFault::InvalidOpcode{
+                        pc: self.program_counter,
+                        instruction
+                    });
                 }
             }
         }
-        self.program_counter += 1;
+        if !jumped {
+            self.program_counter += 1;
+        }
+        self.cycles += self.cycle_costs.cost(get_opcode(op), words);
+        Ok(())
     }
     
     fn cmov(&mut self, ra: usize, rb: usize, rc: usize){
@@ -226,97 +679,173 @@ impl CPU{
         }
     }
     
-    unsafe fn load(&mut self, ram: *mut RAM, ra: usize, rb: usize, rc: usize){
+    fn load(&mut self, ram: &mut RAM, ra: usize, rb: usize, rc: usize) -> Result<(), CpuFault>{
         let seg_id = self.registers[rb];
         let index = self.registers[rc];
-        self.registers[ra] = (*ram).get(seg_id as usize, index as usize) as u64;
+        self.registers[ra] = ram.try_get(seg_id as usize, index as usize)
+            .map_err(|e| CpuFault::from_memory_error(e, self.program_counter))? & self.word_mask();
+        Ok(())
     }
     
-    unsafe fn store(&mut self, ram: *mut RAM, ra: usize, rb: usize, rc: usize){
+    fn store(&mut self, ram: &mut RAM, ra: usize, rb: usize, rc: usize) -> Result<(), CpuFault>{
         let seg_id = self.registers[ra] as usize;
         let index = self.registers[rb] as usize;
         let value = self.registers[rc];
-        (*ram).set(seg_id, index, value);
+        ram.try_set(seg_id, index, value)
+            .map_err(|e| CpuFault::from_memory_error(e, self.program_counter))?;
+        
+        if let Some(tracer) = &mut self.tracer {
+            tracer.write(seg_id, index, value);
+        }
+        Ok(())
     }
     
     fn add(&mut self, ra: usize, rb: usize, rc: usize){
         let vb = self.registers[rb];
         let vc = self.registers[rc];
-        self.registers[ra] = ((vb as u128 + vc as u128) % (1_u128 << 64)) as u64;
+        self.registers[ra] = vb.wrapping_add(vc) & self.word_mask();
     }
     
     fn mul(&mut self, ra: usize, rb: usize, rc: usize){
         let vb = self.registers[rb];
         let vc = self.registers[rc];
-        self.registers[ra] = ((vb as u128 * vc as u128) % (1_u128 << 64)) as u64;
+        self.registers[ra] = vb.wrapping_mul(vc) & self.word_mask();
     }
     
-    fn div(&mut self, ra: usize, rb: usize, rc: usize){
+    fn div(&mut self, ra: usize, rb: usize, rc: usize) -> Result<(), CpuFault>{
         let vb = self.registers[rb];
         let vc = self.registers[rc];
         if vc == 0 {
-            panic!("Division by 0!");
+            return Err(CpuFault::DivideByZero{ pc: self.program_counter });
         }
-        self.registers[ra] = ((vb as u128 / vc as u128) % (1_u128 << 64)) as u64;
+        self.registers[ra] = (vb / vc) & self.word_mask();
+        Ok(())
     }
     
     fn nand(&mut self, ra: usize, rb: usize, rc: usize){
         let vb = self.registers[rb];
         let vc = self.registers[rc];
-        self.registers[ra] = !(vb & vc);
+        self.registers[ra] = !(vb & vc) & self.word_mask();
     }
     
     pub fn halt(&mut self){
         self.halt_flag = true;
     }
     
-    unsafe fn map_seg(&mut self, ram: *mut RAM, rb: usize, rc: usize){
-        let word_count = self.registers[rc];
-        let seg_id = (*ram).request_segment(word_count as usize) as u64;
+    /// Returns the number of words mapped.
+    fn map_seg(&mut self, ram: &mut RAM, rb: usize, rc: usize) -> Result<usize, CpuFault>{
+        let word_count = self.registers[rc] as usize;
+        let seg_id = ram.request_segment(word_count)
+            .map_err(|e| CpuFault::from_memory_error(e, self.program_counter))? as u64;
         self.registers[rb] = seg_id;
+        
+        if let Some(tracer) = &mut self.tracer {
+            tracer.map(seg_id as usize, word_count);
+        }
+        Ok(word_count)
     }
     
-    unsafe fn unmap_seg(&mut self, ram: *mut RAM, rc: usize){
-        let seg_id = self.registers[rc];
-        (*ram).release_segment(seg_id as usize);
+    /// Returns the number of words unmapped.
+    fn unmap_seg(&mut self, ram: &mut RAM, rc: usize) -> Result<usize, CpuFault>{
+        let seg_id = self.registers[rc] as usize;
+        let word_count = ram.segment_length(seg_id).unwrap_or(0);
+        ram.release_segment(seg_id)
+            .map_err(|e| CpuFault::from_memory_error(e, self.program_counter))?;
+        
+        if let Some(tracer) = &mut self.tracer {
+            tracer.unmap(seg_id);
+        }
+        Ok(word_count)
     }
     
-    fn out(&self, rc: usize){
-        if (self.registers[rc] as u32) > 255 {
-            panic!("Value in rc is greater than 255!");
+    fn out(&mut self, bus: &mut dyn Bus, rc: usize) -> Result<(), CpuFault>{
+        if self.registers[rc] > 255 {
+            return Err(CpuFault::OutputRange{
+                pc: self.program_counter,
+                value: self.registers[rc]
+            });
         }
     
-        print!("{}", std::char::from_u32(self.registers[rc] as u32).unwrap());
+        match self.output_port{
+            Some(port) => { bus.port_write(port, self.registers[rc]); }
+            None => {
+                // Written as a raw byte so values above 127 reach the host unchanged.
+                stdout().write_all(&[self.registers[rc] as u8]).unwrap();
+            }
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.output(self.registers[rc] as u8);
+        }
+        Ok(())
     }
     
     fn await_in(&mut self, rc: usize){
-        let val = stdin().bytes().next();
+        // Make sure any prompt has been shown before blocking on input.
+        stdout().flush().unwrap();
+        let val = match &mut self.replay {
+            Some(replay) => { replay.pop_front().flatten() }
+            None => { stdin().bytes().next().map(|value| value.unwrap()) }
+        };
         
+        if let Some(tracer) = &mut self.tracer {
+            tracer.input(val);
+        }
         match val{
             None => {
-                self.registers[rc] = (((1 as u128) << 64) - 1) as u64;
+                self.registers[rc] = self.word_mask();
             }
             Some(value) => {
-                self.registers[rc] = value.unwrap() as u64;
+                self.registers[rc] = value as u64;
             }
         }
     }
     
-    unsafe fn load_program(&mut self, ram: *mut RAM, rb: usize, rc :usize){
+    /// Replaces `m[0]` with `m[r[b]]` and jumps to `r[c]`.
+    ///
+    /// The segment is shared copy-on-write, so a far jump costs nothing
+    /// until either segment is written. Returns the number of words loaded.
+    fn load_program(&mut self, ram: &mut RAM, rb: usize, rc :usize) -> Result<usize, CpuFault>{
         let vb = self.registers[rb];
         let vc = self.registers[rc];
+        let mut word_count = 0;
         
-        if vb == 0{
-            self.program_counter = vc - 1;
-            return
+        if vb != 0{
+            ram.try_duplicate_segment(vb as usize, 0)
+                .map_err(|e| CpuFault::from_memory_error(e, self.program_counter))?;
+            word_count = ram.segment_length(0).unwrap_or(0);
+            
+            if let Some(tracer) = &mut self.tracer {
+                tracer.load_program(vb as usize);
+            }
         }
-
-        (*ram).duplicate_segment(vb as usize, 0);
-        self.program_counter = vc - 1;
+        
+        self.program_counter = vc;
+        Ok(word_count)
+    }
+    
+    fn load_val(&mut self, rl: usize, lv: u64){
+        self.registers[rl] = lv & self.word_mask();
     }
     
-    fn load_val(&mut self, rl: usize, lv: u32){
-        self.registers[rl] = lv as u64;
+    /// Reads port `r[c]` into `r[a]`. A port no part handles reads as all
+    /// ones.
+    fn port_in(&mut self, bus: &mut dyn Bus, ra: usize, rc: usize){
+        let port = self.registers[rc];
+        let value = match self.replay_ports.as_mut().and_then(|replay| replay.pop_front()) {
+            Some(value) => { value }
+            None => { bus.port_read(port).unwrap_or(u64::MAX) }
+        } & self.word_mask();
+        
+        if let Some(tracer) = &mut self.tracer {
+            tracer.port_read(port, value);
+        }
+        self.registers[ra] = value;
+    }
+    
+    /// Writes `r[c]` to port `r[b]`. Writes to a port no part handles are
+    /// dropped.
+    fn port_out(&mut self, bus: &mut dyn Bus, rb: usize, rc: usize){
+        bus.port_write(self.registers[rb], self.registers[rc]);
     }
     
     pub fn print_state(&self){
@@ -332,26 +861,221 @@ impl CPU{
         
     }
 }
-// 
-// #[cfg(test)]
-// mod tests{
-//     use crate::cpu::{get_bits, mask};
-// 
-//     #[test]
-//     fn mask_test(){
-//         assert_eq!(mask(5), 0b11111);
-//         assert_eq!(mask(10), 0b1111111111);
-//         assert_eq!(mask(20), 0b11111111111111111111);
-//         assert_eq!(mask(32), 0b11111111111111111111111111111111);
-//     }
-// 
-//     #[test]
-//     fn get_bits_test(){
-//         assert_eq!(get_bits(0b0,5,0), 0);
-//         assert_eq!(get_bits(0b10010,5,0), 0b10010);
-//         assert_eq!(get_bits(0b1001000,5,2), 0b10010);
-//         assert_eq!(get_bits(0b01010000000000000000000000000000,4,28), 0b0101);
-//     }
-//     
-// }
-// 
+
+/// A `Bus` with no ports that calls a closure on every tick.
+struct Ticker<F: FnMut(u64)>(F);
+
+impl<F: FnMut(u64)> Bus for Ticker<F> {
+    fn tick(&mut self, cycles: u64) {
+        (self.0)(cycles);
+    }
+}
+
+impl MachinePart for CPU {
+    fn name(&self) -> &str {
+        "cpu"
+    }
+    
+    fn reset(&mut self) {
+        self.registers.iter_mut().for_each(|r| *r = 0);
+        self.program_counter = 0;
+        self.stack.clear();
+        self.halt_flag = false;
+        self.trap_frame = None;
+        self.cycles = 0;
+    }
+    
+    fn snapshot(&self) -> Vec<StateValue> {
+        let mut state: Vec<StateValue> = self.registers.iter()
+            .enumerate()
+            .map(|(i, r)| (format!("r{i}"), *r))
+            .collect();
+        state.push((String::from("pc"), self.program_counter));
+        state.push((String::from("cycles"), self.cycles));
+        state.push((String::from("halted"), self.halt_flag as u64));
+        state
+    }
+    
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::cpu::{CPU, CPU_Opcode, CpuFault, CycleCosts, FaultKind, FaultPolicy, TrapFrame, get_bits, mask};
+    use crate::ram::RAM;
+
+    fn execute(cpu: &mut CPU, ram: &mut RAM, op: CPU_Opcode, ra: usize, rb: usize, rc: usize){
+        let instruction = cpu.build_instruction(op, ra, rb, rc);
+        cpu.compute(ram, instruction).unwrap();
+    }
+    
+    fn load_value(cpu: &mut CPU, ram: &mut RAM, rl: usize, lv: u32){
+        let instruction = cpu.build_lv_inst(rl, lv);
+        cpu.compute(ram, instruction).unwrap();
+    }
+
+    #[test]
+    fn mask_test(){
+        assert_eq!(mask(5), 0b11111);
+        assert_eq!(mask(10), 0b1111111111);
+        assert_eq!(mask(20), 0b11111111111111111111);
+        assert_eq!(mask(32), 0b11111111111111111111111111111111);
+        assert_eq!(mask(64), u64::MAX);
+    }
+
+    #[test]
+    fn get_bits_test(){
+        assert_eq!(get_bits(0b0,5,0), 0);
+        assert_eq!(get_bits(0b10010,5,0), 0b10010);
+        assert_eq!(get_bits(0b1001000,5,2), 0b10010);
+        assert_eq!(get_bits(0b01010000000000000000000000000000,4,28), 0b0101);
+    }
+    
+    #[test]
+    fn register_width_test(){
+        for width in [8, 16, 32, 64] {
+            let mut cpu = CPU::new(1, width, 8);
+            let mut ram = RAM::new();
+            let word = mask(width as u32);
+            
+            // r[1] = ~(0 & 0) is all ones at the register width
+            execute(&mut cpu, &mut ram, CPU_Opcode::NAND, 1, 0, 0);
+            assert_eq!(cpu.registers[1], word);
+            
+            // all ones + 2 wraps around to 1
+            load_value(&mut cpu, &mut ram, 2, 2);
+            execute(&mut cpu, &mut ram, CPU_Opcode::Add, 3, 1, 2);
+            assert_eq!(cpu.registers[3], 1);
+            
+            // all ones * 2 wraps around to all ones but the lowest bit
+            execute(&mut cpu, &mut ram, CPU_Opcode::Mul, 4, 1, 2);
+            assert_eq!(cpu.registers[4], word - 1);
+            
+            // immediates are wrapped as well
+            load_value(&mut cpu, &mut ram, 5, 0x1ffffff);
+            assert_eq!(cpu.registers[5], 0x1ffffff & word);
+        }
+    }
+    
+    #[test]
+    fn load_wraps_to_register_width_test(){
+        let mut cpu = CPU::new(1, 16, 8);
+        let mut ram = RAM::new();
+        ram.request_segment(1).unwrap();
+        ram.set(0, 0, 0x12345678);
+        
+        execute(&mut cpu, &mut ram, CPU_Opcode::Load, 1, 0, 0);
+        assert_eq!(cpu.registers[1], 0x5678);
+    }
+    
+    #[test]
+    fn load_program_jump_to_zero_test(){
+        let mut cpu = CPU::new(1, 32, 8);
+        let mut ram = RAM::new();
+        ram.request_segment(4).unwrap();
+        cpu.program_counter = 3;
+        
+        execute(&mut cpu, &mut ram, CPU_Opcode::LP, 0, 0, 0);
+        assert_eq!(cpu.program_counter, 0);
+    }
+    
+    #[test]
+    fn load_program_copy_on_write_test(){
+        let mut cpu = CPU::new(1, 32, 8);
+        let mut ram = RAM::new();
+        ram.request_segment(4).unwrap();
+        ram.request_segment(4).unwrap();
+        ram.set(1, 2, 42);
+        
+        load_value(&mut cpu, &mut ram, 1, 1);
+        load_value(&mut cpu, &mut ram, 2, 2);
+        execute(&mut cpu, &mut ram, CPU_Opcode::LP, 0, 1, 2);
+        assert_eq!(cpu.program_counter, 2);
+        assert!(ram.is_shared(0));
+        assert_eq!(ram.get(0, 2), 42);
+        
+        // writing m[1] copies it and leaves the loaded program alone
+        ram.set(1, 2, 7);
+        assert!(!ram.is_shared(0));
+        assert_eq!(ram.get(0, 2), 42);
+        assert_eq!(ram.get(1, 2), 7);
+    }
+    
+    #[test]
+    fn cycle_cost_test(){
+        let mut cpu = CPU::new(1, 32, 8);
+        let mut ram = RAM::new();
+        let mut costs = CycleCosts::uniform(1);
+        costs.set(CPU_Opcode::Div, 10);
+        costs.set(CPU_Opcode::MapSeg, 5);
+        costs.set_per_word(CPU_Opcode::MapSeg, 2);
+        cpu.set_cycle_costs(costs);
+        
+        load_value(&mut cpu, &mut ram, 1, 100);
+        assert_eq!(cpu.cycles(), 1);
+        
+        execute(&mut cpu, &mut ram, CPU_Opcode::Div, 2, 1, 1);
+        assert_eq!(cpu.cycles(), 11);
+        
+        // mapping 100 words costs 5 + 2 * 100
+        execute(&mut cpu, &mut ram, CPU_Opcode::MapSeg, 0, 3, 1);
+        assert_eq!(cpu.cycles(), 216);
+    }
+    
+    #[test]
+    #[should_panic]
+    fn too_few_registers_test(){
+        CPU::new(1, 32, 4);
+    }
+    
+    #[test]
+    fn fault_halt_test(){
+        let mut cpu = CPU::new(1, 32, 8);
+        let mut ram = RAM::new();
+        ram.request_segment(1).unwrap();
+        let instruction = cpu.build_instruction(CPU_Opcode::Div, 1, 2, 3);
+        ram.set(0, 0, instruction as u64);
+        
+        assert_eq!(cpu.step(&mut ram), Err(CpuFault::DivideByZero{ pc: 0 }));
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc(), 0);
+        assert_eq!(cpu.trap_frame(), None);
+    }
+    
+    #[test]
+    fn fault_trap_test(){
+        // The faulting pc doesn't fit in an 8-bit register.
+        let mut cpu = CPU::new(1, 8, 8);
+        let mut ram = RAM::new();
+        ram.request_segment(300).unwrap();
+        let instruction = cpu.build_instruction(CPU_Opcode::Div, 1, 2, 3);
+        ram.set(0, 260, instruction as u64);
+        cpu.set_pc(260);
+        cpu.set_trap_vector(Some(5));
+        cpu.set_fault_policy(FaultKind::DivideByZero, FaultPolicy::Trap);
+        
+        assert_eq!(cpu.step(&mut ram), Ok(()));
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.pc(), 5);
+        assert_eq!(cpu.registers()[5], FaultKind::DivideByZero as u64);
+        assert_eq!(cpu.registers()[6], 260 & 0xff);
+        assert_eq!(cpu.registers()[7], instruction as u64 & 0xff);
+        assert_eq!(cpu.trap_frame(), Some(TrapFrame{
+            fault: CpuFault::DivideByZero{ pc: 260 },
+            pc: 260,
+            instruction
+        }));
+        
+        // Other kinds of fault still halt: r5 now names the unmapped m[1].
+        let load = cpu.build_instruction(CPU_Opcode::Load, 1, 5, 0);
+        ram.set(0, 5, load as u64);
+        assert_eq!(cpu.step(&mut ram), Err(CpuFault::BadSegment{ pc: 5, segment: 1 }));
+        assert!(cpu.is_halted());
+    }
+}
@@ -0,0 +1,454 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, Write};
+use crate::cpu::{CPU, CPU_Opcode, CpuFault};
+use crate::disassembler::decode;
+use crate::machine::{Machine, MachineError};
+use crate::ram::RAM;
+
+/// Why the debugger stopped running the program.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The requested number of instructions were executed.
+    Stepped,
+    /// The program counter reached a breakpoint.
+    Breakpoint(u64),
+    /// The next instruction has an opcode with a breakpoint on it.
+    Opcode(CPU_Opcode, u64),
+    /// A watched word changed. `None` means the word wasn't mapped.
+    Watchpoint { segment: usize, index: usize, old: Option<u64>, new: Option<u64> },
+    Halted,
+    Fault(CpuFault)
+}
+
+impl Display for Stop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let value = |v: &Option<u64>| v.map_or(String::from("unmapped"), |v| format!("{:#x}", v));
+        match self{
+            Stop::Stepped => { write!(f, "stepped") }
+            Stop::Breakpoint(pc) => { write!(f, "breakpoint at {:#x}", pc) }
+            Stop::Opcode(op, pc) => { write!(f, "{} at {:#x}", op.mnemonic(), pc) }
+            Stop::Watchpoint { segment, index, old, new } => {
+                write!(f, "m[{}][{}] changed from {} to {}", segment, index, value(old), value(new))
+            }
+            Stop::Halted => { write!(f, "halted") }
+            Stop::Fault(fault) => { write!(f, "fault: {}", fault) }
+        }
+    }
+}
+
+struct Watchpoint {
+    segment: usize,
+    index: usize,
+    value: Option<u64>
+}
+
+const HELP: &str = "\
+step [n]              execute n instructions (default 1)
+continue              run until a breakpoint, watchpoint, halt or fault
+break <addr|opcode>   stop before the instruction at addr, or before any instruction with that mnemonic
+delete <addr|opcode>  remove a breakpoint
+watch <seg> <idx>     stop after m[seg][idx] changes
+unwatch <seg> <idx>   remove a watchpoint
+breaks                list breakpoints and watchpoints
+regs                  show the registers
+mem <seg> [idx] [n]   show n words of m[seg] from idx (default 0, 16)
+dis [addr] [n]        disassemble n instructions from addr (default around the pc)
+set r<n> <value>      set a register
+set pc <value>        set the program counter
+set m <seg> <idx> <value>   set a word of memory
+quit                  stop debugging
+An empty line repeats the last command. Numbers can be decimal or 0x hex.
+";
+
+/// An interactive monitor that single-steps a `Machine`.
+///
+/// The machine must already be loaded with `Machine::load`.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u64>,
+    opcode_breakpoints: HashSet<CPU_Opcode>,
+    watchpoints: Vec<Watchpoint>,
+    last_command: String,
+    // The pc of the breakpoint last stopped at, which the next run starts past.
+    stopped_at: Option<u64>
+}
+
+fn parse_number(text: &str) -> Result<u64, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => { u64::from_str_radix(hex, 16) },
+        None => { text.parse::<u64>() }
+    };
+    parsed.map_err(|_| format!("`{text}` is not a number"))
+}
+
+fn opcode_named(name: &str) -> Option<CPU_Opcode> {
+    (0..CPU_Opcode::INVALID as u32)
+        .map(|code| decode(code << 28).op)
+        .find(|op| op.mnemonic() == name)
+}
+
+fn cpu(machine: &Machine) -> &CPU {
+    machine.part::<CPU>().expect("the debugger needs a CPU")
+}
+
+fn cpu_mut(machine: &mut Machine) -> &mut CPU {
+    machine.part_mut::<CPU>().expect("the debugger needs a CPU")
+}
+
+fn ram(machine: &Machine) -> &RAM {
+    machine.part::<RAM>().expect("the debugger needs RAM")
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn add_opcode_breakpoint(&mut self, op: CPU_Opcode) {
+        self.opcode_breakpoints.insert(op);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Watches `m[segment][index]`, which doesn't have to be mapped yet.
+    pub fn add_watchpoint(&mut self, machine: &Machine, segment: usize, index: usize) {
+        let value = ram(machine).try_get(segment, index).ok();
+        self.remove_watchpoint(segment, index);
+        self.watchpoints.push(Watchpoint { segment, index, value });
+    }
+
+    pub fn remove_watchpoint(&mut self, segment: usize, index: usize) {
+        self.watchpoints.retain(|w| (w.segment, w.index) != (segment, index));
+    }
+
+    /// Executes up to `count` instructions, or until something stops the
+    /// program if `count` is `None`.
+    ///
+    /// Breakpoints are checked before every instruction, except the one
+    /// the last run stopped at so running from a breakpoint gets past it.
+    pub fn run(&mut self, machine: &mut Machine, count: Option<u64>) -> Stop {
+        let stop = self.run_until_stop(machine, count);
+        self.stopped_at = match stop {
+            Stop::Breakpoint(pc) | Stop::Opcode(_, pc) => { Some(pc) },
+            _ => { None }
+        };
+        stop
+    }
+
+    fn run_until_stop(&mut self, machine: &mut Machine, count: Option<u64>) -> Stop {
+        let mut executed = 0;
+        loop {
+            let cpu = cpu(machine);
+            if cpu.is_halted() {
+                return Stop::Halted;
+            }
+            if count == Some(executed) {
+                return Stop::Stepped;
+            }
+
+            let pc = cpu.pc();
+            if executed > 0 || self.stopped_at != Some(pc) {
+                if self.breakpoints.contains(&pc) {
+                    return Stop::Breakpoint(pc);
+                }
+                if let Ok(word) = ram(machine).try_get(0, pc as usize) {
+                    let op = decode(word as u32).op;
+                    if self.opcode_breakpoints.contains(&op) {
+                        return Stop::Opcode(op, pc);
+                    }
+                }
+            }
+
+            match machine.step() {
+                Ok(()) => {}
+                Err(MachineError::Fault(fault)) => { return Stop::Fault(fault) }
+                Err(MachineError::Build(error)) => { panic!("the debugger needs a machine that can run: {error}") }
+            }
+            executed += 1;
+
+            let ram = ram(machine);
+            for watchpoint in self.watchpoints.iter_mut() {
+                let value = ram.try_get(watchpoint.segment, watchpoint.index).ok();
+                if value != watchpoint.value {
+                    let old = watchpoint.value;
+                    watchpoint.value = value;
+                    return Stop::Watchpoint { segment: watchpoint.segment, index: watchpoint.index, old, new: value };
+                }
+            }
+        }
+    }
+
+    /// Reads commands from `input` until it ends or `quit` is entered.
+    pub fn repl<R: BufRead, W: Write>(&mut self, machine: &mut Machine, input: R, mut output: W) -> std::io::Result<()> {
+        writeln!(output, "Type `help` for a list of commands.")?;
+        self.disassemble(machine, None, 1, &mut output)?;
+
+        let mut lines = input.lines();
+        loop {
+            write!(output, "(warch) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => { line? },
+                None => { return Ok(()) }
+            };
+            if !self.command(machine, &line, &mut output)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs one command, writing its output and any error to `output`.
+    /// Returns false when the command was `quit`.
+    pub fn command<W: Write>(&mut self, machine: &mut Machine, line: &str, output: &mut W) -> std::io::Result<bool> {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { String::from(line.trim()) };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match self.run_command(machine, &words, output) {
+            Ok(keep_going) => { Ok(keep_going) }
+            Err(CommandError::Io(e)) => { Err(e) }
+            Err(CommandError::Usage(message)) => {
+                writeln!(output, "{message}")?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn run_command<W: Write>(&mut self, machine: &mut Machine, words: &[&str], output: &mut W) -> Result<bool, CommandError> {
+        let number = |i: usize| -> Result<u64, CommandError> {
+            let text = words.get(i).ok_or(CommandError::Usage(String::from("missing argument, see `help`")))?;
+            parse_number(text).map_err(CommandError::Usage)
+        };
+        let optional = |i: usize, default: u64| -> Result<u64, CommandError> {
+            if i < words.len() { number(i) } else { Ok(default) }
+        };
+        let range_end = |start: u64, count: u64| -> Result<u64, CommandError> {
+            start.checked_add(count).ok_or(CommandError::Usage(format!("{start:#x} + {count} is out of range")))
+        };
+
+        match words.first().copied().unwrap_or("") {
+            "" => {}
+            "s" | "step" => {
+                let stop = self.run(machine, Some(optional(1, 1)?));
+                self.report(machine, stop, output)?;
+            }
+            "c" | "continue" => {
+                let stop = self.run(machine, None);
+                self.report(machine, stop, output)?;
+            }
+            "b" | "break" | "d" | "delete" => {
+                let adding = words[0].starts_with('b');
+                let target = words.get(1).ok_or(CommandError::Usage(String::from("missing address or opcode")))?;
+                match opcode_named(target) {
+                    Some(op) if adding => { self.opcode_breakpoints.insert(op); }
+                    Some(op) => { self.opcode_breakpoints.remove(&op); }
+                    None if adding => { self.breakpoints.insert(number(1)?); }
+                    None => { self.remove_breakpoint(number(1)?); }
+                }
+            }
+            "w" | "watch" => {
+                self.add_watchpoint(machine, number(1)? as usize, number(2)? as usize);
+            }
+            "unwatch" => {
+                self.remove_watchpoint(number(1)? as usize, number(2)? as usize);
+            }
+            "breaks" => {
+                for pc in self.breakpoints.iter() {
+                    writeln!(output, "break {:#x}", pc)?;
+                }
+                let mut ops: Vec<&str> = self.opcode_breakpoints.iter().map(|op| op.mnemonic()).collect();
+                ops.sort();
+                for op in ops {
+                    writeln!(output, "break {op}")?;
+                }
+                for w in self.watchpoints.iter() {
+                    writeln!(output, "watch {} {}", w.segment, w.index)?;
+                }
+            }
+            "r" | "regs" => {
+                let cpu = cpu(machine);
+                for (i, value) in cpu.registers().iter().enumerate() {
+                    writeln!(output, "r{:<2} {:#010x}  {}", i, value, value)?;
+                }
+                writeln!(output, "pc  {:#010x}  cycles {}", cpu.pc(), cpu.cycles())?;
+            }
+            "m" | "mem" => {
+                let segment = number(1)? as usize;
+                let start = optional(2, 0)?;
+                let end = range_end(start, optional(3, 16)?)?;
+                let ram = ram(machine);
+                let length = ram.segment_length(segment).map_err(|e| CommandError::Usage(e.to_string()))?;
+
+                let start = start as usize;
+                let end = end.min(length as u64) as usize;
+                for row in (start..end).step_by(8) {
+                    let words: Vec<String> = (row..(row + 8).min(end))
+                        .map(|i| format!("{:08x}", ram.try_get(segment, i).unwrap_or(0)))
+                        .collect();
+                    writeln!(output, "m[{}][{:#06x}]  {}", segment, row, words.join(" "))?;
+                }
+            }
+            "x" | "dis" => {
+                let start = if words.len() > 1 { Some(number(1)?) } else { None };
+                let count = optional(2, 10)?;
+                if let Some(start) = start {
+                    range_end(start, count)?;
+                }
+                self.disassemble(machine, start, count, output)?;
+            }
+            "set" => {
+                let target = words.get(1).copied().unwrap_or("");
+                if target == "pc" {
+                    let pc = number(2)?;
+                    cpu_mut(machine).set_pc(pc);
+                }
+                else if target == "m" {
+                    let (segment, index, value) = (number(2)? as usize, number(3)? as usize, number(4)?);
+                    let ram = machine.part_mut::<RAM>().expect("the debugger needs RAM");
+                    ram.try_set(segment, index, value).map_err(|e| CommandError::Usage(e.to_string()))?;
+                }
+                else {
+                    let register = target.strip_prefix('r').and_then(|r| r.parse::<usize>().ok())
+                        .ok_or(CommandError::Usage(String::from("set what? r<n>, pc or m")))?;
+                    let value = number(2)?;
+                    if !cpu_mut(machine).set_register(register, value) {
+                        return Err(CommandError::Usage(format!("there is no register r{register}")));
+                    }
+                }
+            }
+            "h" | "help" => { write!(output, "{HELP}")?; }
+            "q" | "quit" => { return Ok(false) }
+            command => { return Err(CommandError::Usage(format!("unknown command `{command}`, see `help`"))) }
+        }
+        Ok(true)
+    }
+
+    /// Prints why the program stopped and the instruction it stopped at.
+    fn report<W: Write>(&self, machine: &Machine, stop: Stop, output: &mut W) -> std::io::Result<()> {
+        if stop != Stop::Stepped {
+            writeln!(output, "{stop}")?;
+        }
+        if stop != Stop::Halted {
+            self.disassemble(machine, None, 1, output)?;
+        }
+        Ok(())
+    }
+
+    /// Disassembles `count` words of `m[0]` from `start`, or from around the
+    /// program counter. The pc is marked with `>` and breakpoints with `*`.
+    fn disassemble<W: Write>(&self, machine: &Machine, start: Option<u64>, count: u64, output: &mut W) -> std::io::Result<()> {
+        let cpu = cpu(machine);
+        let pc = cpu.pc();
+        let start = start.unwrap_or(if count == 1 { pc } else { pc.saturating_sub(count / 2) });
+
+        // Near the top of the address space the range is cut short rather
+        // than wrapping around.
+        for address in start..start.saturating_add(count) {
+            let word = match ram(machine).try_get(0, address as usize) {
+                Ok(word) => { word as u32 },
+                Err(_) => { break }
+            };
+            let marker = if address == pc { '>' } else { ' ' };
+            let breakpoint = if self.breakpoints.contains(&address) { '*' } else { ' ' };
+            writeln!(output, "{}{} {:08x}: {:08x}  {}", marker, breakpoint, address, word, cpu.disassemble(word))?;
+        }
+        Ok(())
+    }
+}
+
+enum CommandError {
+    Io(std::io::Error),
+    /// A bad command, shown to the user.
+    Usage(String)
+}
+
+impl From<std::io::Error> for CommandError {
+    fn from(error: std::io::Error) -> Self {
+        CommandError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::assembler::assemble;
+    use crate::cpu::{CPU, CPU_Opcode};
+    use crate::debugger::{Debugger, Stop};
+    use crate::harddrive::HardDrive;
+    use crate::machine::{Machine, MachineBuilder};
+    use crate::scheduler::UNLIMITED;
+
+    fn load(source: &str) -> Machine {
+        let mut machine = MachineBuilder::new()
+            .cpu(CPU::new(UNLIMITED, 32, 8))
+            .ram(crate::ram::RAM::new())
+            .drive(HardDrive::from_bytes(assemble(source).unwrap().to_bytes()))
+            .um_mode(true)
+            .build()
+            .unwrap();
+        machine.load().unwrap();
+        machine
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints_test(){
+        let mut machine = load("
+                movi r1, 4
+                map r2, r1
+                movi r3, 9
+                movi r4, 2
+                store r2, r4, r3
+                halt
+        ");
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(2);
+        debugger.add_opcode_breakpoint(CPU_Opcode::HALT);
+        debugger.add_watchpoint(&machine, 1, 2);
+
+        assert_eq!(debugger.run(&mut machine, Some(1)), Stop::Stepped);
+        assert_eq!(debugger.run(&mut machine, None), Stop::Watchpoint{ segment: 1, index: 2, old: None, new: Some(0) });
+        assert_eq!(debugger.run(&mut machine, None), Stop::Breakpoint(2));
+        assert_eq!(debugger.run(&mut machine, None), Stop::Watchpoint{ segment: 1, index: 2, old: Some(0), new: Some(9) });
+        assert_eq!(debugger.run(&mut machine, None), Stop::Opcode(CPU_Opcode::HALT, 5));
+        assert_eq!(debugger.run(&mut machine, None), Stop::Halted);
+    }
+
+    #[test]
+    fn commands_test(){
+        let mut machine = load("movi r1, 1\nadd r2, r1, r1\nhalt\n");
+        let mut debugger = Debugger::new();
+        let mut output = Vec::new();
+
+        for command in ["set r1 0x20", "set pc 1", "step", "", "regs", "dis 0 2"] {
+            assert!(debugger.command(&mut machine, command, &mut output).unwrap());
+        }
+        assert!(!debugger.command(&mut machine, "quit", &mut output).unwrap());
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("halted"));
+        assert!(output.contains("r2  0x00000040  64"));
+        assert!(output.contains("   00000000: d2000001  movi r1, 1"));
+        assert_eq!(machine.part::<CPU>().unwrap().registers()[2], 0x40);
+    }
+
+    #[test]
+    fn bad_range_test(){
+        let mut machine = load("halt\n");
+        let mut debugger = Debugger::new();
+        let mut output = Vec::new();
+
+        for command in ["mem 0 0xffffffffffffffff 2", "dis 0xffffffffffffffff 2", "set pc 0xffffffffffffffff", "dis"] {
+            assert!(debugger.command(&mut machine, command, &mut output).unwrap());
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("is out of range").count(), 2);
+        assert!(output.contains("0xffffffffffffffff + 2 is out of range"));
+    }
+}
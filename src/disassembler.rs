@@ -0,0 +1,326 @@
+use std::collections::{BTreeSet, HashMap};
+use crate::cpu::{CPU_Opcode, get_bits, get_opcode, mask};
+
+/// The fields of an instruction word.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Decoded {
+    pub op: CPU_Opcode,
+    pub ra: usize,
+    pub rb: usize,
+    pub rc: usize,
+    pub rl: usize,
+    pub value: u32
+}
+
+pub fn decode(instruction: u32) -> Decoded {
+    Decoded {
+        op: get_opcode(get_bits(instruction, 4, 28)),
+        ra: get_bits(instruction, 3, 6) as usize,
+        rb: get_bits(instruction, 3, 3) as usize,
+        rc: get_bits(instruction, 3, 0) as usize,
+        rl: get_bits(instruction, 3, 25) as usize,
+        value: get_bits(instruction, 25, 0)
+    }
+}
+
+/// Splits a program image into big-endian 32-bit words, the format
+/// `Machine::boot` loads into `m[0]`. Trailing bytes that don't make up a
+/// whole word are ignored.
+pub fn words_from_bytes(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Encodes the fields of an instruction the way the assembler does. Bits
+/// that `op` doesn't use are left clear.
+fn encode(decoded: &Decoded) -> u32 {
+    let Decoded { op, ra, rb, rc, rl, value } = *decoded;
+    let (ra, rb, rc, rl) = (ra as u32, rb as u32, rc as u32, rl as u32);
+    let op_bits = (op as u32) << 28;
+    match op{
+        CPU_Opcode::CMov | CPU_Opcode::Load | CPU_Opcode::Store |
+        CPU_Opcode::Add | CPU_Opcode::Mul | CPU_Opcode::Div | CPU_Opcode::NAND => {
+            op_bits | ra << 6 | rb << 3 | rc
+        }
+        CPU_Opcode::MapSeg | CPU_Opcode::LP | CPU_Opcode::PortOut => { op_bits | rb << 3 | rc }
+        CPU_Opcode::PortIn => { op_bits | ra << 6 | rc }
+        CPU_Opcode::UnmapSeg | CPU_Opcode::Out | CPU_Opcode::In => { op_bits | rc }
+        CPU_Opcode::LV => { op_bits | rl << 25 | value }
+        CPU_Opcode::HALT | CPU_Opcode::INVALID => { op_bits }
+    }
+}
+
+/// Whether `instruction` is a valid instruction that assembles back into
+/// the same word from its disassembly.
+fn round_trips(instruction: u32) -> bool {
+    let decoded = decode(instruction);
+    decoded.op != CPU_Opcode::INVALID && encode(&decoded) == instruction
+}
+
+/// The operands of `instruction` in assembler syntax, e.g. `r1, r2, r3`.
+fn operands(decoded: &Decoded) -> String {
+    let Decoded { op, ra, rb, rc, rl, value } = *decoded;
+    match op{
+        CPU_Opcode::CMov | CPU_Opcode::Load | CPU_Opcode::Store |
+        CPU_Opcode::Add | CPU_Opcode::Mul | CPU_Opcode::Div | CPU_Opcode::NAND => {
+            format!("r{ra}, r{rb}, r{rc}")
+        }
+        CPU_Opcode::MapSeg | CPU_Opcode::LP | CPU_Opcode::PortOut => { format!("r{rb}, r{rc}") }
+        CPU_Opcode::PortIn => { format!("r{ra}, r{rc}") }
+        CPU_Opcode::UnmapSeg | CPU_Opcode::Out | CPU_Opcode::In => { format!("r{rc}") }
+        CPU_Opcode::LV => { format!("r{rl}, {value}") }
+        CPU_Opcode::HALT | CPU_Opcode::INVALID => { String::new() }
+    }
+}
+
+/// Disassembles one instruction into assembler syntax, e.g. `add r1, r2, r3`.
+/// Words that are not instructions, or that have bits set their
+/// instruction doesn't use, become a `.word` directive so that the text
+/// assembles back into the same word.
+pub fn disassemble_instruction(instruction: u32) -> String {
+    let decoded = decode(instruction);
+    if !round_trips(instruction) {
+        return format!(".word {:#010x}", instruction);
+    }
+    match decoded.op{
+        CPU_Opcode::HALT => { String::from(decoded.op.mnemonic()) }
+        op => { format!("{} {}", op.mnemonic(), operands(&decoded)) }
+    }
+}
+
+/// A register value known at some point of the program, and the address of
+/// the `movi` that loaded it, if it came straight from one.
+type Known = Option<(u64, Option<usize>)>;
+
+/// A disassembled program image.
+///
+/// Jump targets are found by following the constants loaded into registers
+/// through straight-line code: a `run` whose `$r[b]` is known to be 0 and
+/// whose `$r[c]` is known is a jump inside the program, and its target gets
+/// a label. Knowledge is dropped after every `run` and `halt`, and at every
+/// label since it may be reached from elsewhere with other values, so only
+/// jumps whose target is computed in the same block are resolved.
+pub struct Disassembly {
+    words: Vec<u32>,
+    targets: BTreeSet<usize>,
+    jumps: HashMap<usize, usize>, // address of run -> target
+    label_loads: HashMap<usize, usize> // address of movi -> target
+}
+
+impl Disassembly {
+    pub fn new(words: Vec<u32>) -> Self {
+        let mut disassembly = Disassembly {
+            words,
+            targets: BTreeSet::new(),
+            jumps: HashMap::new(),
+            label_loads: HashMap::new()
+        };
+        disassembly.find_jumps();
+        disassembly
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self::new(words_from_bytes(bytes))
+    }
+
+    pub fn words(&self) -> &[u32] {
+        &self.words
+    }
+
+    /// The label given to `address`, if it is a jump target.
+    pub fn label(&self, address: usize) -> Option<String> {
+        if self.targets.contains(&address) {
+            Some(format!("L{:04x}", address))
+        }
+        else {
+            None
+        }
+    }
+
+    /// The target of the `run` at `address`, if it could be resolved.
+    pub fn jump_target(&self, address: usize) -> Option<usize> {
+        self.jumps.get(&address).copied()
+    }
+
+    fn find_jumps(&mut self) {
+        // Dropping knowledge at more addresses can only resolve fewer jumps,
+        // so this settles once a pass finds no new target.
+        let mut boundaries = BTreeSet::new();
+        loop {
+            self.find_jumps_between(&boundaries);
+            if self.targets.is_subset(&boundaries) {
+                break;
+            }
+            boundaries.extend(self.targets.iter().copied());
+        }
+    }
+
+    /// Resolves jumps, dropping what is known about the registers at every
+    /// address in `boundaries`.
+    fn find_jumps_between(&mut self, boundaries: &BTreeSet<usize>) {
+        self.targets.clear();
+        self.jumps.clear();
+        self.label_loads.clear();
+        let word = mask(32);
+        // The machine starts with every register cleared.
+        let mut known: [Known; 8] = [Some((0, None)); 8];
+
+        for (address, instruction) in self.words.iter().enumerate() {
+            if boundaries.contains(&address) {
+                known = [None; 8];
+            }
+            let Decoded { op, ra, rb, rc, rl, value } = decode(*instruction);
+            // Data words are not run, but nothing is known after them.
+            let op = if round_trips(*instruction) { op } else { CPU_Opcode::INVALID };
+            let fold = |known: &[Known; 8], f: fn(u64, u64) -> Option<u64>| -> Known {
+                match (known[rb], known[rc]) {
+                    (Some((b, _)), Some((c, _))) => { f(b, c).map(|v| (v & word, None)) }
+                    _ => { None }
+                }
+            };
+
+            match op{
+                CPU_Opcode::LV => {
+                    known[rl] = Some((value as u64, Some(address)));
+                }
+                CPU_Opcode::Add => { known[ra] = fold(&known, |b, c| Some(b.wrapping_add(c))); }
+                CPU_Opcode::Mul => { known[ra] = fold(&known, |b, c| Some(b.wrapping_mul(c))); }
+                CPU_Opcode::Div => { known[ra] = fold(&known, |b, c| b.checked_div(c)); }
+                CPU_Opcode::NAND => { known[ra] = fold(&known, |b, c| Some(!(b & c))); }
+                CPU_Opcode::CMov => {
+                    if known[rc].map(|(c, _)| c != 0) == Some(true) {
+                        known[ra] = known[rb];
+                    }
+                    else if known[rc].is_none() && known[ra] != known[rb] {
+                        known[ra] = None;
+                    }
+                }
+                CPU_Opcode::Load => { known[ra] = None; }
+                CPU_Opcode::MapSeg => { known[rb] = None; }
+                CPU_Opcode::In => { known[rc] = None; }
+                CPU_Opcode::PortIn => { known[ra] = None; }
+                CPU_Opcode::Store | CPU_Opcode::UnmapSeg | CPU_Opcode::Out | CPU_Opcode::PortOut => {}
+                CPU_Opcode::LP => {
+                    if let (Some((0, _)), Some((target, load))) = (known[rb], known[rc]) {
+                        let target = target as usize;
+                        if target < self.words.len() {
+                            self.targets.insert(target);
+                            self.jumps.insert(address, target);
+                            if let Some(load) = load {
+                                self.label_loads.insert(load, target);
+                            }
+                        }
+                    }
+                    known = [None; 8];
+                }
+                CPU_Opcode::HALT | CPU_Opcode::INVALID => {
+                    known = [None; 8];
+                }
+            }
+        }
+    }
+
+    /// A listing with the address, raw word and disassembly of every word.
+    pub fn listing(&self) -> String {
+        let mut listing = String::new();
+        for (address, instruction) in self.words.iter().enumerate() {
+            if let Some(label) = self.label(address) {
+                listing.push_str(&format!("{label}:\n"));
+            }
+            listing.push_str(&format!("{:08x}: {:08x}  {}", address, instruction, disassemble_instruction(*instruction)));
+            if let Some(target) = self.jump_target(address) {
+                listing.push_str(&format!("  ; -> {}", self.label(target).unwrap()));
+            }
+            listing.push('\n');
+        }
+        listing
+    }
+
+    /// Assembler source that assembles back into the same image.
+    ///
+    /// `movi`s that load a resolved jump target refer to its label, so the
+    /// source can be edited without breaking the jumps.
+    pub fn source(&self) -> String {
+        let mut source = String::new();
+        for (address, instruction) in self.words.iter().enumerate() {
+            if let Some(label) = self.label(address) {
+                source.push_str(&format!("{label}:\n"));
+            }
+            let decoded = decode(*instruction);
+            let text = match self.label_loads.get(&address) {
+                Some(target) => { format!("movi r{}, {}", decoded.rl, self.label(*target).unwrap()) }
+                None => { disassemble_instruction(*instruction) }
+            };
+            source.push_str(&format!("    {text}\n"));
+        }
+        source
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::assembler::assemble;
+    use crate::disassembler::{Disassembly, disassemble_instruction};
+
+    #[test]
+    fn disassemble_instruction_test(){
+        assert_eq!(disassemble_instruction(0x300000d1), "add r3, r2, r1");
+        assert_eq!(disassemble_instruction(0xd2000005), "movi r1, 5");
+        assert_eq!(disassemble_instruction(0x80000011), "map r2, r1");
+        assert_eq!(disassemble_instruction(0x70000000), "halt");
+        assert_eq!(disassemble_instruction(0xe0000081), "portin r2, r1");
+        assert_eq!(disassemble_instruction(0xf0000011), "portout r2, r1");
+        // Unused bits that are set make a word data.
+        assert_eq!(disassemble_instruction(0x7fffffff), ".word 0x7fffffff");
+        assert_eq!(disassemble_instruction(0x12345678), ".word 0x12345678");
+        assert_eq!(disassemble_instruction(0xf0000211), ".word 0xf0000211");
+    }
+
+    #[test]
+    fn round_trip_test(){
+        let original = assemble("
+            start:  movi r7, end
+                    run r0, r7
+                    .word 0x12345678, 0x7fffffff, 0xffffffff, 0x10000078
+                    portin r2, r1
+            end:    halt
+        ").unwrap();
+        let source = Disassembly::new(original.words.clone()).source();
+        assert_eq!(assemble(&source).unwrap().words, original.words);
+    }
+
+    #[test]
+    fn jump_label_test(){
+        let disassembly = Disassembly::new(vec![
+            0xde000003, // movi r7, 3
+            0xc0000007, // run r0, r7
+            0x70000000, // halt
+            0xa0000001, // output r1
+            0x70000000  // halt
+        ]);
+
+        assert_eq!(disassembly.jump_target(1), Some(3));
+        assert_eq!(disassembly.source(),
+                   "    movi r7, L0003\n    run r0, r7\n    halt\nL0003:\n    output r1\n    halt\n");
+    }
+
+    #[test]
+    fn shared_label_test(){
+        let disassembly = Disassembly::new(vec![
+            0xde000005, // movi r7, 5
+            0xc0000007, // run r0, r7
+            0xde000007, // movi r7, 7
+            0xa0000001, // output r1
+            0xa0000001, // output r1
+            0xc0000007, // run r0, r7   r7 is 5 or 7 here
+            0x70000000, // halt
+            0x70000000  // halt
+        ]);
+
+        assert_eq!(disassembly.jump_target(1), Some(5));
+        assert_eq!(disassembly.jump_target(5), None);
+        assert_eq!(disassembly.label(7), None);
+        assert!(!disassembly.listing().contains("L0007"));
+    }
+}
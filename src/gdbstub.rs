@@ -0,0 +1,629 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use crate::cpu::{CPU, FaultKind};
+use crate::debugger::{Debugger, Stop};
+use crate::machine::Machine;
+use crate::ram::RAM;
+
+/// Instructions run between checks for an interrupt from gdb.
+const CHUNK: u64 = 10_000;
+
+/// The most words a single watchpoint packet may cover. Every watched word
+/// is checked after each instruction, so larger ranges are refused.
+const MAX_WATCH_WORDS: usize = 64;
+
+/// A connection to gdb: a TCP or Unix socket.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Frames `data` as a remote serial protocol packet: `$data#checksum`.
+pub fn packet(data: &str) -> String {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    format!("${}#{:02x}", data, checksum)
+}
+
+/// The flat address gdb sees for `m[segment][index]`.
+///
+/// Segment `s` starts at `s << 32`, and every word takes `word_bytes`
+/// bytes (the register width in bytes), stored big-endian.
+pub fn flat_address(segment: usize, index: usize, word_bytes: u64) -> u64 {
+    ((segment as u64) << 32) | (index as u64 * word_bytes)
+}
+
+/// The segment, word index and byte within the word of a flat address.
+fn locate(address: u64, word_bytes: u64) -> (usize, usize, usize) {
+    let offset = address & 0xffff_ffff;
+    ((address >> 32) as usize, (offset / word_bytes) as usize, (offset % word_bytes) as usize)
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+fn hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Escapes the characters that can't appear in a binary reply.
+fn escape_binary(data: &str) -> String {
+    let mut escaped = String::new();
+    for c in data.chars() {
+        if matches!(c, '$' | '#' | '}' | '*') {
+            escaped.push('}');
+            escaped.push((c as u8 ^ 0x20) as char);
+        }
+        else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// The signal reported to gdb for a fault.
+fn signal(kind: FaultKind) -> u8 {
+    match kind{
+        FaultKind::InvalidOpcode => { 4 }, // SIGILL
+        FaultKind::DivideByZero => { 8 }, // SIGFPE
+        FaultKind::BadSegment | FaultKind::OutOfBounds => { 11 }, // SIGSEGV
+        FaultKind::OutputRange | FaultKind::OutOfMemory | FaultKind::TooManySegments => { 6 } // SIGABRT
+    }
+}
+
+fn cpu(machine: &Machine) -> &CPU {
+    machine.part::<CPU>().expect("the gdb stub needs a CPU")
+}
+
+fn cpu_mut(machine: &mut Machine) -> &mut CPU {
+    machine.part_mut::<CPU>().expect("the gdb stub needs a CPU")
+}
+
+fn ram_mut(machine: &mut Machine) -> &mut RAM {
+    machine.part_mut::<RAM>().expect("the gdb stub needs RAM")
+}
+
+/// A gdb remote serial protocol server for a loaded `Machine`.
+///
+/// The registers are r0 to rN at the CPU's register width followed by a
+/// 64-bit pc holding the flat address of the next instruction, all sent
+/// big-endian. Memory is the flat view described in `flat_address`.
+/// Breakpoints, watchpoints and stepping are done by a `Debugger`.
+pub struct GdbStub {
+    debugger: Debugger,
+    last_stop: String,
+    no_ack: bool,
+    closing: bool
+}
+
+impl Default for GdbStub {
+    fn default() -> Self {
+        GdbStub {
+            debugger: Debugger::new(),
+            last_stop: String::from("S05"),
+            no_ack: false,
+            closing: false
+        }
+    }
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        GdbStub::default()
+    }
+
+    /// The target description of the machine's register file.
+    pub fn target_xml(cpu: &CPU) -> String {
+        let bits = cpu.register_width();
+        let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.warch.cpu\">\n");
+        for i in 0..cpu.registers().len() {
+            xml.push_str(&format!("    <reg name=\"r{i}\" bitsize=\"{bits}\" type=\"uint{bits}\" regnum=\"{i}\"/>\n"));
+        }
+        xml.push_str(&format!("    <reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>\n", cpu.registers().len()));
+        xml.push_str("  </feature>\n</target>\n");
+        xml
+    }
+
+    fn word_bytes(machine: &Machine) -> u64 {
+        cpu(machine).register_width() as u64 / 8
+    }
+
+    /// Waits for gdb to connect to `address` and serves it until it detaches.
+    ///
+    /// `address` is a TCP address such as `127.0.0.1:1234`, a bare port
+    /// number for localhost, or `unix:<path>` for a Unix socket.
+    pub fn listen(&mut self, machine: &mut Machine, address: &str) -> std::io::Result<()> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path)?;
+            eprintln!("Waiting for gdb on {address}");
+            let (stream, _) = listener.accept()?;
+            return self.serve(machine, stream);
+        }
+
+        let address = match address.parse::<u16>() {
+            Ok(port) => { format!("127.0.0.1:{port}") },
+            Err(_) => { String::from(address) }
+        };
+        let listener = TcpListener::bind(&address)?;
+        eprintln!("Waiting for gdb on {address}");
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(machine, stream)
+    }
+
+    /// Serves one gdb connection until it detaches, kills the program or
+    /// disconnects.
+    pub fn serve<S: Connection>(&mut self, machine: &mut Machine, stream: S) -> std::io::Result<()> {
+        let mut link = Link { stream, pending: VecDeque::new() };
+        self.closing = false;
+
+        while !self.closing {
+            let data = match link.read_packet()? {
+                None => { return Ok(()) }
+                Some(Event::Interrupt) => {
+                    link.send(&format!("S{:02x}", 2))?;
+                    continue
+                }
+                Some(Event::Corrupt) => {
+                    if !self.no_ack {
+                        link.stream.write_all(b"-")?;
+                    }
+                    continue
+                }
+                Some(Event::Packet(data)) => { data }
+            };
+            if !self.no_ack {
+                link.stream.write_all(b"+")?;
+            }
+
+            let reply = self.handle_packet(machine, &data, &mut || link.interrupted());
+            if let Some(reply) = reply {
+                link.send(&reply)?;
+            }
+        }
+        link.stream.flush()
+    }
+
+    /// Handles the contents of one packet and returns the reply, if the
+    /// packet has one. `interrupted` is polled while the program runs and
+    /// stops it when it returns true.
+    pub fn handle_packet(&mut self, machine: &mut Machine, data: &str, interrupted: &mut dyn FnMut() -> bool) -> Option<String> {
+        let word_bytes = Self::word_bytes(machine);
+        let (command, args) = data.split_at(data.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command{
+            "?" => { self.last_stop.clone() }
+            "q" => { self.query(machine, args) }
+            "Q" if args == "StartNoAckMode" => {
+                self.no_ack = true;
+                String::from("OK")
+            }
+            "H" => { String::from("OK") }
+            "g" => {
+                let cpu = cpu(machine);
+                let mut registers: String = cpu.registers().iter()
+                    .map(|r| format!("{:0width$x}", r, width = word_bytes as usize * 2))
+                    .collect();
+                registers.push_str(&format!("{:016x}", cpu.pc() * word_bytes));
+                registers
+            }
+            "G" => {
+                let width = word_bytes as usize * 2;
+                let count = cpu(machine).registers().len();
+                let values: Option<Vec<u64>> = (0..=count)
+                    .map(|i| {
+                        let digits = if i < count { width } else { 16 };
+                        args.get(i * width..i * width + digits).and_then(parse_hex)
+                    })
+                    .collect();
+                match values {
+                    Some(values) => {
+                        let cpu = cpu_mut(machine);
+                        for (i, value) in values[..count].iter().enumerate() {
+                            cpu.set_register(i, *value);
+                        }
+                        cpu.set_pc(values[count] / word_bytes);
+                        String::from("OK")
+                    }
+                    None => { String::from("E01") }
+                }
+            }
+            "p" => {
+                let cpu = cpu(machine);
+                match parse_hex(args).map(|n| n as usize) {
+                    Some(n) if n < cpu.registers().len() => {
+                        format!("{:0width$x}", cpu.registers()[n], width = word_bytes as usize * 2)
+                    }
+                    Some(n) if n == cpu.registers().len() => { format!("{:016x}", cpu.pc() * word_bytes) }
+                    _ => { String::from("E01") }
+                }
+            }
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| Some((parse_hex(n)? as usize, parse_hex(value)?)));
+                let cpu = cpu_mut(machine);
+                match parsed {
+                    Some((n, value)) if n < cpu.registers().len() => {
+                        cpu.set_register(n, value);
+                        String::from("OK")
+                    }
+                    Some((n, value)) if n == cpu.registers().len() => {
+                        cpu.set_pc(value / word_bytes);
+                        String::from("OK")
+                    }
+                    _ => { String::from("E01") }
+                }
+            }
+            "m" => {
+                match args.split_once(',').and_then(|(a, l)| Some((parse_hex(a)?, parse_hex(l)?))) {
+                    Some((address, length)) => { self.read_memory(machine, address, length) }
+                    None => { String::from("E01") }
+                }
+            }
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, bytes)| {
+                    let (address, _) = range.split_once(',')?;
+                    Some((parse_hex(address)?, hex_bytes(bytes)?))
+                });
+                match parsed {
+                    Some((address, bytes)) => { self.write_memory(machine, address, &bytes) }
+                    None => { String::from("E01") }
+                }
+            }
+            "Z" | "z" => { self.breakpoint(machine, command == "Z", args) }
+            "s" | "c" => {
+                if let Some(address) = parse_hex(args) {
+                    cpu_mut(machine).set_pc(address / word_bytes);
+                }
+                let count = if command == "s" { Some(1) } else { None };
+                self.resume(machine, count, interrupted)
+            }
+            "D" => {
+                self.closing = true;
+                String::from("OK")
+            }
+            "k" => {
+                self.closing = true;
+                return None;
+            }
+            "v" if args == "Kill" || args.starts_with("Kill;") => {
+                self.closing = true;
+                String::from("OK")
+            }
+            // vCont and anything else unsupported: gdb falls back to s and c.
+            _ => { String::new() }
+        };
+        Some(reply)
+    }
+
+    fn query(&self, machine: &Machine, args: &str) -> String {
+        if args.starts_with("Supported") {
+            return String::from("PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+");
+        }
+        if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = Self::target_xml(cpu(machine));
+            let (offset, length) = match range.split_once(',').and_then(|(o, l)| Some((parse_hex(o)? as usize, parse_hex(l)? as usize))) {
+                Some(range) => { range },
+                None => { return String::from("E01") }
+            };
+            if offset >= xml.len() {
+                return String::from("l");
+            }
+            let end = (offset + length).min(xml.len());
+            let more = if end < xml.len() { "m" } else { "l" };
+            return format!("{}{}", more, escape_binary(&xml[offset..end]));
+        }
+        match args{
+            "Attached" => { String::from("1") }
+            "C" => { String::from("QC1") }
+            "fThreadInfo" => { String::from("m1") }
+            "sThreadInfo" => { String::from("l") }
+            _ => { String::new() }
+        }
+    }
+
+    fn read_memory(&self, machine: &mut Machine, address: u64, length: u64) -> String {
+        let word_bytes = Self::word_bytes(machine);
+        let ram = ram_mut(machine);
+        let mut reply = String::new();
+        for address in address..address.saturating_add(length) {
+            let (segment, index, byte) = locate(address, word_bytes);
+            match ram.try_get(segment, index) {
+                Ok(word) => {
+                    let shift = 8 * (word_bytes as usize - 1 - byte);
+                    reply.push_str(&format!("{:02x}", (word >> shift) as u8));
+                }
+                // A short read, or an error if nothing could be read.
+                Err(_) => { break }
+            }
+        }
+        if reply.is_empty() && length > 0 { String::from("E14") } else { reply }
+    }
+
+    fn write_memory(&self, machine: &mut Machine, address: u64, bytes: &[u8]) -> String {
+        let word_bytes = Self::word_bytes(machine);
+        let ram = ram_mut(machine);
+        for (address, value) in (address..).zip(bytes.iter()) {
+            let (segment, index, byte) = locate(address, word_bytes);
+            let shift = 8 * (word_bytes as usize - 1 - byte);
+            let word = match ram.try_get(segment, index) {
+                Ok(word) => { word },
+                Err(_) => { return String::from("E14") }
+            };
+            let word = (word & !(0xff << shift)) | ((*value as u64) << shift);
+            if ram.try_set(segment, index, word).is_err() {
+                return String::from("E14");
+            }
+        }
+        String::from("OK")
+    }
+
+    /// `Z`/`z` packets: software and hardware breakpoints (types 0 and 1),
+    /// which must be in `m[0]`, and write watchpoints (type 2) of up to
+    /// `MAX_WATCH_WORDS` words.
+    fn breakpoint(&mut self, machine: &Machine, insert: bool, args: &str) -> String {
+        let word_bytes = Self::word_bytes(machine);
+        let fields: Vec<&str> = args.split(',').collect();
+        let (kind, address, length) = match fields.as_slice() {
+            [kind, address, length, ..] => {
+                match (parse_hex(address), parse_hex(length)) {
+                    (Some(address), Some(length)) => { (*kind, address, length) },
+                    _ => { return String::from("E01") }
+                }
+            }
+            _ => { return String::from("E01") }
+        };
+
+        match kind{
+            "0" | "1" => {
+                let (segment, index, _) = locate(address, word_bytes);
+                if segment != 0 {
+                    return String::from("E01");
+                }
+                if insert {
+                    self.debugger.add_breakpoint(index as u64);
+                }
+                else {
+                    self.debugger.remove_breakpoint(index as u64);
+                }
+            }
+            "2" => {
+                let end = match address.checked_add(length.max(1) - 1) {
+                    Some(end) => { end },
+                    None => { return String::from("E01") }
+                };
+                let (segment, first, _) = locate(address, word_bytes);
+                let (end_segment, last, _) = locate(end, word_bytes);
+                if end_segment != segment || last - first >= MAX_WATCH_WORDS {
+                    return String::from("E01");
+                }
+                for index in first..=last {
+                    if insert {
+                        self.debugger.add_watchpoint(machine, segment, index);
+                    }
+                    else {
+                        self.debugger.remove_watchpoint(segment, index);
+                    }
+                }
+            }
+            _ => { return String::new() }
+        }
+        String::from("OK")
+    }
+
+    /// Runs the program and returns the stop reply.
+    fn resume(&mut self, machine: &mut Machine, count: Option<u64>, interrupted: &mut dyn FnMut() -> bool) -> String {
+        let stop = match count {
+            Some(count) => { Some(self.debugger.run(machine, Some(count))) }
+            None => {
+                loop {
+                    let stop = self.debugger.run(machine, Some(CHUNK));
+                    if stop != Stop::Stepped {
+                        break Some(stop);
+                    }
+                    if interrupted() {
+                        break None;
+                    }
+                }
+            }
+        };
+
+        let word_bytes = Self::word_bytes(machine);
+        self.last_stop = match stop {
+            None => { format!("S{:02x}", 2) } // SIGINT
+            Some(Stop::Stepped) | Some(Stop::Opcode(..)) => { String::from("S05") }
+            Some(Stop::Breakpoint(_)) => { String::from("T05swbreak:;") }
+            Some(Stop::Watchpoint { segment, index, .. }) => {
+                format!("T05watch:{:x};", flat_address(segment, index, word_bytes))
+            }
+            Some(Stop::Halted) => { String::from("W00") }
+            Some(Stop::Fault(fault)) => { format!("S{:02x}", signal(fault.kind())) }
+        };
+        self.last_stop.clone()
+    }
+}
+
+enum Event {
+    Packet(String),
+    /// A packet whose checksum didn't match.
+    Corrupt,
+    /// gdb sent ^C.
+    Interrupt
+}
+
+/// The socket to gdb, with the bytes read but not yet handled.
+struct Link<S: Connection> {
+    stream: S,
+    pending: VecDeque<u8>
+}
+
+impl<S: Connection> Link<S> {
+    fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut buffer = [0u8; 4096];
+        let read = self.stream.read(&mut buffer)?;
+        self.pending.extend(&buffer[..read]);
+        Ok(self.pending.pop_front())
+    }
+
+    /// Reads the next packet or interrupt. `None` means gdb disconnected.
+    fn read_packet(&mut self) -> std::io::Result<Option<Event>> {
+        loop {
+            match self.read_byte()? {
+                None => { return Ok(None) }
+                Some(0x03) => { return Ok(Some(Event::Interrupt)) }
+                Some(b'$') => { break }
+                // Acknowledgements and noise.
+                Some(_) => {}
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte()? {
+                None => { return Ok(None) }
+                Some(b'#') => { break }
+                Some(byte) => { data.push(byte) }
+            }
+        }
+        let mut checksum = String::new();
+        for _ in 0..2 {
+            match self.read_byte()? {
+                None => { return Ok(None) }
+                Some(byte) => { checksum.push(byte as char) }
+            }
+        }
+
+        let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if u8::from_str_radix(&checksum, 16).ok() != Some(sum) {
+            return Ok(Some(Event::Corrupt));
+        }
+        Ok(Some(Event::Packet(String::from_utf8_lossy(&data).into_owned())))
+    }
+
+    /// Checks, without blocking, whether gdb has sent ^C.
+    fn interrupted(&mut self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut buffer = [0u8; 256];
+        let result = self.stream.read(&mut buffer);
+        let _ = self.stream.set_nonblocking(false);
+
+        match result {
+            Ok(read) => {
+                self.pending.extend(&buffer[..read]);
+                if let Some(i) = self.pending.iter().position(|b| *b == 0x03) {
+                    self.pending.remove(i);
+                    return true;
+                }
+                // A disconnect also stops the program.
+                read == 0
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => { false }
+            Err(_) => { true }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> std::io::Result<()> {
+        self.stream.write_all(packet(data).as_bytes())?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+    use crate::gdbstub::{GdbStub, packet};
+    use crate::harddrive::HardDrive;
+    use crate::machine::{Machine, MachineBuilder};
+    use crate::ram::RAM;
+    use crate::scheduler::UNLIMITED;
+
+    fn load(source: &str) -> Machine {
+        let mut machine = MachineBuilder::new()
+            .cpu(CPU::new(UNLIMITED, 32, 8))
+            .ram(RAM::new())
+            .drive(HardDrive::from_bytes(assemble(source).unwrap().to_bytes()))
+            .um_mode(true)
+            .build()
+            .unwrap();
+        machine.load().unwrap();
+        machine
+    }
+
+    fn request(stub: &mut GdbStub, machine: &mut Machine, data: &str) -> String {
+        stub.handle_packet(machine, data, &mut || false).unwrap()
+    }
+
+    #[test]
+    fn packet_test(){
+        assert_eq!(packet("OK"), "$OK#9a");
+        assert_eq!(packet(""), "$#00");
+    }
+
+    #[test]
+    fn registers_and_memory_test(){
+        let mut machine = load("movi r1, 0x41\nhalt\n");
+        let mut stub = GdbStub::new();
+
+        assert_eq!(request(&mut stub, &mut machine, "s"), "S05");
+        assert_eq!(request(&mut stub, &mut machine, "p1"), "00000041");
+        assert_eq!(request(&mut stub, &mut machine, "p8"), "0000000000000004");
+        assert_eq!(request(&mut stub, &mut machine, "m0,8"), "d200004170000000");
+        assert_eq!(request(&mut stub, &mut machine, "M3,1:42"), "OK");
+        assert_eq!(request(&mut stub, &mut machine, "m0,4"), "d2000042");
+        assert_eq!(request(&mut stub, &mut machine, "m100000000,4"), "E14");
+        assert!(request(&mut stub, &mut machine, "qXfer:features:read:target.xml:0,1000").contains("<reg name=\"pc\""));
+    }
+
+    #[test]
+    fn breakpoint_and_continue_test(){
+        let mut machine = load("movi r1, 1\nmovi r2, 2\nhalt\n");
+        let mut stub = GdbStub::new();
+
+        assert_eq!(request(&mut stub, &mut machine, "Z0,8,4"), "OK");
+        assert_eq!(request(&mut stub, &mut machine, "c"), "T05swbreak:;");
+        assert_eq!(request(&mut stub, &mut machine, "p8"), "0000000000000008");
+        assert_eq!(request(&mut stub, &mut machine, "?"), "T05swbreak:;");
+        assert_eq!(request(&mut stub, &mut machine, "c"), "W00");
+    }
+
+    #[test]
+    fn watchpoint_range_test(){
+        let mut machine = load("halt\n");
+        let mut stub = GdbStub::new();
+
+        assert_eq!(request(&mut stub, &mut machine, "Z2,ffffffffffffffff,2"), "E01");
+        assert_eq!(request(&mut stub, &mut machine, "Z2,1fffffffc,8"), "E01");
+        assert_eq!(request(&mut stub, &mut machine, "Z2,100000000,8"), "OK");
+        assert_eq!(request(&mut stub, &mut machine, "z2,100000000,8"), "OK");
+
+        // Huge ranges are refused rather than watched word by word.
+        assert_eq!(request(&mut stub, &mut machine, "Z2,0,ffffffff"), "E01");
+        assert_eq!(request(&mut stub, &mut machine, "Z2,0,100"), "OK");
+        assert_eq!(request(&mut stub, &mut machine, "Z2,0,104"), "E01");
+    }
+}
@@ -1,12 +1,21 @@
-use std::io::{Read, stdin};
-use std::sync::mpsc::{Receiver, Sender};
+use std::any::Any;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
 use std::thread::sleep;
-use std::time::Duration;
-use crate::machine::{Machine, MachineWrapper};
+use std::time::{Duration, Instant};
+use crossbeam::channel::Sender;
+use crate::cpu::CpuFault;
+use crate::display_data_helper::{DChar, GLYPH_SIZE};
+use crate::machine::VideoOutWrapper;
 use crate::ram::RAM;
+use crate::scheduler::Scheduler;
+use crate::video_mode::{default_palette, PixelFormat, VideoMode, PALETTE_SIZE};
+use crate::MachinePart::{MachinePart, StateValue};
 
-#[derive(Copy, Clone, Debug)]
-pub enum GPU_Opcode { 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GPU_Opcode {
     NOP,
     Clear, // Clear screen display
     MovC, // Move cursor to r[b], r[c]
@@ -50,19 +59,149 @@ pub fn get_opcode(code: u32) -> GPU_Opcode {
     }
 }
 
+/// The segment of `dd_ram` holding the GPU's program.
+pub const PROGRAM_SEGMENT: usize = 0;
+/// The segment of `dd_ram` holding the framebuffer, laid out as the video
+/// mode's `PixelFormat` says.
+pub const FRAME_SEGMENT: usize = 1;
+/// The segment of `dd_ram` holding the character written to each text cell.
+pub const TEXT_SEGMENT: usize = 2;
+
+/// The size in pixels of a text cell.
+pub const CELL_WIDTH: usize = GLYPH_SIZE;
+pub const CELL_HEIGHT: usize = GLYPH_SIZE;
+
+/// The framebuffer is drawn by the GPU's program.
+pub const MODE_GRAPHICS: u64 = 0;
+/// The framebuffer shows the text cells, drawn with the built-in font.
+pub const MODE_TEXT: u64 = 1;
+
+/// The I/O ports the CPU drives the GPU through with `portin` and `portout`.
+pub const GPU_PORTS: Range<u64> = 0x10..0x22;
+/// Write 1 to start the GPU at the entry point or 0 to stop it; reads the
+/// GPU's `status`.
+pub const PORT_CONTROL: u64 = 0x10;
+/// The program counter the GPU starts at.
+pub const PORT_ENTRY: u64 = 0x11;
+/// Selects the GPU register read and written through `PORT_REGISTER_DATA`.
+pub const PORT_REGISTER: u64 = 0x12;
+pub const PORT_REGISTER_DATA: u64 = 0x13;
+/// Selects the segment of the display data RAM read and written through
+/// `PORT_DATA`.
+pub const PORT_SEGMENT: u64 = 0x14;
+/// The index in the selected segment of the next `PORT_DATA` access.
+pub const PORT_INDEX: u64 = 0x15;
+/// Reads or writes the selected word, then moves the index to the next one.
+pub const PORT_DATA: u64 = 0x16;
+/// Write a length to map a segment of that many words, which is selected
+/// at index 0. If it can't be mapped the selected segment reads as all ones.
+pub const PORT_MAP: u64 = 0x17;
+/// Write a segment id to unmap it.
+pub const PORT_UNMAP: u64 = 0x18;
+/// Write a segment id to copy that segment over the GPU's program.
+pub const PORT_PROGRAM: u64 = 0x19;
+/// Waits for the GPU to stop, then reads its `status`.
+pub const PORT_WAIT: u64 = 0x1a;
+/// `MODE_GRAPHICS` or `MODE_TEXT`.
+pub const PORT_MODE: u64 = 0x1b;
+/// Write a character to print it on the text console at the cursor.
+pub const PORT_CONSOLE: u64 = 0x1c;
+/// The colours (bits 0 to 7) and inverse and blink flags (bits 8 and 9)
+/// given to characters printed without colours of their own.
+pub const PORT_ATTRIBUTE: u64 = 0x1d;
+/// The `PixelFormat` code of the framebuffer.
+pub const PORT_FORMAT: u64 = 0x1e;
+/// The resolution, as the width in pixels shifted left 16 bits plus the
+/// height. Changing it or the format clears the screen.
+pub const PORT_RESOLUTION: u64 = 0x1f;
+/// Selects the palette entry read and written through `PORT_PALETTE_DATA`.
+pub const PORT_PALETTE_INDEX: u64 = 0x20;
+/// Reads or writes the selected palette entry as 0xRRGGBB, then selects the
+/// next one.
+pub const PORT_PALETTE_DATA: u64 = 0x21;
+
+/// The largest width or height a video mode can have.
+pub const MAX_RESOLUTION: usize = 1024;
+
+/// `status` of a GPU that isn't running: never started, finished or stopped.
+pub const STATUS_IDLE: u64 = 0;
+pub const STATUS_RUNNING: u64 = 1;
+/// `status` of a GPU stopped by a fault.
+pub const STATUS_FAULT: u64 = 2;
+
+/// How often the GPU sends its framebuffer to the screen.
+const FRAME_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / 60);
+/// Blinking text and the cursor are shown and hidden every this many frames.
+const BLINK_FRAMES: u64 = 30;
+
+/// The graphics processor.
+///
+/// The GPU runs its own program from segment 0 of its display data RAM on a
+/// thread of its own, started with `start`, at `clock_speed` instructions per
+/// second. Segment 1 is the framebuffer that is sent to the screen in the
+/// current `VideoMode`, and
+/// segment 2 holds the text cells written by `Print` (see `DChar`). The
+/// program stops when it faults, when `stop` is called, or when it jumps to
+/// the instruction it is at, which is how a GPU program finishes.
+///
+/// The thread keeps sending frames, and drawing the text cells in text
+/// mode, until the GPU is reset or dropped. Senders must be added before
+/// it first starts.
 pub struct GPU{
     clock_speed: u64,
     register_width: usize,
+    x_size: usize, // the resolution at power on
+    y_size: usize,
+    core: Arc<Mutex<GpuCore>>,
+    senders: Vec<Sender<VideoOutWrapper>>,
+    running: Arc<AtomicBool>, // the program is running
+    powered: Arc<AtomicBool>, // the thread is running
+    thread: Option<JoinHandle<()>>,
+    selected_register: usize,
+    selected_segment: usize,
+    index: usize
+}
+
+/// The state the GPU thread shares with the machine.
+struct GpuCore{
     registers: Vec<u64>,
     program_counter: u64,
-    stack: Vec<u64>,
-    senders: Vec<Sender<Vec<[u8; 3]>>>,
-    x_size: usize,
-    y_size: usize,
+    cursor: (usize, usize),
+    columns: usize,
+    rows: usize,
+    video: VideoMode,
+    palette: Vec<[u8; 3]>,
+    palette_index: usize,
+    mode: u64,
+    attribute: u64,
+    word_mask: u64,
+    idle: bool,
+    fault: Option<CpuFault>,
+    drawn: Option<DrawnText>,
     dd_ram: RAM // Display Data RAM
 }
 
+/// What the text console was last drawn from. The segments are shared with
+/// `dd_ram`, so they stay the same allocation until either is written.
+struct DrawnText{
+    cells: Arc<Vec<u64>>,
+    frame: Arc<Vec<u64>>,
+    cursor: (usize, usize),
+    blink_on: bool,
+    palette: Vec<[u8; 3]>
+}
+
+impl DrawnText{
+    fn is_same(&self, other: &DrawnText) -> bool {
+        Arc::ptr_eq(&self.cells, &other.cells) && Arc::ptr_eq(&self.frame, &other.frame)
+            && self.cursor == other.cursor && self.blink_on == other.blink_on && self.palette == other.palette
+    }
+}
+
 pub fn mask(width: u64) -> u64{
+    if width >= 64 {
+        return u64::MAX;
+    }
     (1 << width) - 1
 }
 
@@ -74,264 +213,1032 @@ pub fn check_fits(val: u64, bits: u32) -> bool {
     val < 2_u32.pow(bits) as u64
 }
 
+/// Encodes a GPU instruction with register operands.
+pub fn build_instruction(op: GPU_Opcode, ra: usize, rb: usize, rc: usize) -> u32{
+    if op == GPU_Opcode::INVALID || ra > 7 || rb > 7 || rc > 7 {
+        panic!("Bad instruction parameters!");
+    }
+    ((op as u32) << 28) | (ra << 6) as u32 | (rb << 3) as u32 | rc as u32
+}
+
+/// Encodes a `MovI` loading `lv` into register `rl`.
+pub fn build_lv_inst(rl: usize, lv: u32) -> u32{
+    if !check_fits(lv as u64, 25) || rl > 7 {
+        panic!("value won't fit into 25 bits!")
+    }
+    ((GPU_Opcode::MovI as u32) << 28) | (rl << 25) as u32 | lv
+}
+
 impl GPU{
     pub fn new(clock_speed: u64, register_width: usize, register_count: usize, x_res: usize, y_res: usize) -> Self{
-        let registers = vec![0u64; register_count];
-        let stack: Vec<u64> = Vec::new();
-        let dd_ram = RAM::new();
+        let video = VideoMode::new(PixelFormat::Rgb, x_res, y_res, register_width);
+
+        let mut dd_ram = RAM::new();
+        dd_ram.request_segment(0).unwrap(); // segment 0 program
+        dd_ram.request_segment(video.frame_words()).unwrap(); // segment 1 display data
+        dd_ram.request_segment((x_res / CELL_WIDTH) * (y_res / CELL_HEIGHT)).unwrap(); // segment 2 text cells
+
+        let core = GpuCore{
+            registers: vec![0u64; register_count],
+            program_counter: 0,
+            cursor: (0, 0),
+            columns: x_res / CELL_WIDTH,
+            rows: y_res / CELL_HEIGHT,
+            video,
+            palette: default_palette(),
+            palette_index: 0,
+            mode: MODE_GRAPHICS,
+            attribute: 0,
+            word_mask: mask(register_width as u64),
+            idle: true,
+            fault: None,
+            drawn: None,
+            dd_ram
+        };
 
         GPU{
             clock_speed,
             register_width,
-            registers,
-            stack,
-            program_counter: 0,
-            senders: Vec::new(),
-            dd_ram,
             x_size: x_res,
-            y_size: y_res
-        }
-    }
-    
-    // pub fn init(&mut self, b1: Receiver<String>, a2: Sender<Option<GpuSignal>>){
-    //     let signal = GpuSignal {
-    //         signal: self as *mut GPU
-    //     };
-    // 
-    //     match b1.recv(){
-    //         Ok(string) => {
-    //             a2.send(Some(signal)).ok();
-    //         }
-    //         Err(e) => {
-    //             a2.send(None).ok();
-    //         }
-    //     }
-    // }
-    
-    pub fn add_signaler(&mut self, sender: Sender<Vec<[u8; 3]>>) {
+            y_size: y_res,
+            core: Arc::new(Mutex::new(core)),
+            senders: Vec::new(),
+            running: Arc::new(AtomicBool::new(false)),
+            powered: Arc::new(AtomicBool::new(false)),
+            thread: None,
+            selected_register: 0,
+            selected_segment: 0,
+            index: 0
+        }
+    }
+
+    /// The display resolution as (width, height) in pixels.
+    pub fn resolution(&self) -> (usize, usize) {
+        let video = self.core().video;
+        (video.width, video.height)
+    }
+
+    pub fn video_mode(&self) -> VideoMode {
+        self.core().video
+    }
+
+    /// Switches the framebuffer to `format` at `width` by `height` pixels,
+    /// clearing the framebuffer and text cells. Returns false, leaving the
+    /// mode as it was, if the size is 0 or above `MAX_RESOLUTION` or the
+    /// format's pixels don't fit in a register.
+    pub fn set_video_mode(&mut self, format: PixelFormat, width: usize, height: usize) -> bool {
+        let video = VideoMode::new(format, width, height, self.register_width);
+        if !video.is_valid() || !(1..=MAX_RESOLUTION).contains(&width) || !(1..=MAX_RESOLUTION).contains(&height) {
+            return false;
+        }
+        self.core().set_video_mode(video);
+        true
+    }
+
+    pub fn palette(&self) -> Vec<[u8; 3]> {
+        self.core().palette.clone()
+    }
+
+    /// Sets the colour of palette entry `index`, if there is one.
+    pub fn set_palette_entry(&mut self, index: usize, colour: [u8; 3]) {
+        if let Some(entry) = self.core().palette.get_mut(index) {
+            *entry = colour;
+        }
+    }
+
+    pub fn register_width(&self) -> usize {
+        self.register_width
+    }
+
+    pub fn add_signaler(&mut self, sender: Sender<VideoOutWrapper>) {
         self.senders.push(sender);
     }
-    
-    pub unsafe fn run(&mut self){
 
-        
-        self.dd_ram.request_segment(self.x_size * self.y_size); // segment 0 display data
+    fn core(&self) -> MutexGuard<'_, GpuCore> {
+        self.core.lock().unwrap()
+    }
+
+    /// Replaces the program in segment 0 and points the GPU at its first
+    /// instruction.
+    pub fn load_program(&mut self, program: &[u32]) {
+        let mut core = self.core();
+        let ram = &mut core.dd_ram;
+        let segment = ram.request_segment(program.len()).unwrap();
+        for (i, word) in program.iter().enumerate() {
+            ram.set(segment, i, *word as u64);
+        }
+        ram.duplicate_segment(segment, PROGRAM_SEGMENT);
+        ram.release_segment(segment).unwrap();
+
+        core.program_counter = 0;
+        core.fault = None;
+        core.idle = false;
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.core().program_counter
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        let mut core = self.core();
+        core.program_counter = pc;
+        core.idle = false;
+    }
+
+    pub fn registers(&self) -> Vec<u64> {
+        self.core().registers.clone()
+    }
+
+    /// Sets register `index`, wrapped to the register width. Returns false
+    /// if there is no such register.
+    pub fn set_register(&mut self, index: usize, value: u64) -> bool {
+        let mut core = self.core();
+        let mask = core.word_mask;
+        match core.registers.get_mut(index) {
+            Some(register) => { *register = value & mask; true }
+            None => { false }
+        }
+    }
+
+    /// The text cursor as (column, row).
+    pub fn cursor(&self) -> (usize, usize) {
+        self.core().cursor
+    }
+
+    /// A copy of segment `segment` of the display data RAM.
+    pub fn segment(&self, segment: usize) -> Option<Vec<u64>> {
+        let core = self.core();
+        core.dd_ram.is_mapped(segment).then(|| core.dd_ram.to_vec(segment))
+    }
+
+    /// True once the program has finished by jumping to itself.
+    pub fn is_idle(&self) -> bool {
+        self.core().idle
+    }
+
+    /// The fault that stopped the GPU, if any.
+    pub fn fault(&self) -> Option<CpuFault> {
+        self.core().fault
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// `STATUS_RUNNING`, `STATUS_FAULT` or `STATUS_IDLE`.
+    pub fn status(&self) -> u64 {
+        if self.is_running() {
+            STATUS_RUNNING
+        }
+        else if self.fault().is_some() {
+            STATUS_FAULT
+        }
+        else {
+            STATUS_IDLE
+        }
+    }
+
+    /// Blocks until the program stops.
+    pub fn wait(&self) {
+        while self.is_running() {
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Executes the instruction at the program counter on the calling thread.
+    pub fn step(&mut self) -> Result<(), CpuFault> {
+        self.core().step()
+    }
+
+    /// Starts running the program on the GPU thread from the current
+    /// program counter. Does nothing if the GPU is already running.
+    pub fn start(&mut self) {
+        if self.is_running() {
+            return;
+        }
+        {
+            let mut core = self.core();
+            core.idle = false;
+            core.fault = None;
+        }
+        self.running.store(true, Ordering::SeqCst);
+        self.power_on();
+    }
+
+    /// Stops the program after the instruction it is executing.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn mode(&self) -> u64 {
+        self.core().mode
+    }
+
+    /// Switches between `MODE_GRAPHICS` and `MODE_TEXT`.
+    pub fn set_mode(&mut self, mode: u64) {
+        self.core().mode = mode;
+        if mode == MODE_TEXT {
+            self.power_on();
+        }
+    }
+
+    /// Prints a character on the text console, see `PORT_CONSOLE`.
+    pub fn print(&mut self, value: u64) {
+        self.core().put_char(value);
+    }
+
+    /// Draws the text cells into the framebuffer now if in text mode, as the
+    /// GPU thread does every frame.
+    pub fn refresh(&mut self) {
+        self.core().refresh(true);
+    }
+
+    /// Starts the GPU thread if it isn't running yet.
+    fn power_on(&mut self) {
+        if self.thread.is_some() {
+            return;
+        }
+        self.powered.store(true, Ordering::SeqCst);
+
+        let core = self.core.clone();
+        let running = self.running.clone();
+        let powered = self.powered.clone();
+        let senders = self.senders.clone();
+        let clock_speed = self.clock_speed;
+        self.thread = Some(std::thread::spawn(move || {
+            run(core, running, powered, senders, clock_speed);
+        }));
+    }
+
+    /// Stops the program and the GPU thread, and waits for it to finish.
+    fn power_off(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.powered.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// The GPU thread: runs the program while `running` is set, and sends the
+/// framebuffer to `senders` every frame until `powered` is cleared.
+fn run(core: Arc<Mutex<GpuCore>>, running: Arc<AtomicBool>, powered: Arc<AtomicBool>, senders: Vec<Sender<VideoOutWrapper>>, clock_speed: u64){
+    let mut scheduler = Scheduler::new(clock_speed);
+    let mut last_frame = Instant::now();
+    let mut frames = 0u64;
+    let mut was_running = false;
+
+    while powered.load(Ordering::SeqCst) {
+        let mut finished = false;
+        if running.load(Ordering::SeqCst) {
+            if !was_running {
+                scheduler.reset();
+            }
+            let mut core = core.lock().unwrap();
+            if let Err(fault) = core.step() {
+                eprintln!("GPU fault: {}", fault);
+            }
+            finished = core.idle || core.fault.is_some();
+            drop(core);
+
+            if finished {
+                running.store(false, Ordering::SeqCst);
+            }
+            was_running = !finished;
+            scheduler.account(1);
+        }
+        else {
+            was_running = false;
+            sleep(Duration::from_millis(1));
+        }
+
+        if finished || last_frame.elapsed() >= FRAME_INTERVAL {
+            frames += 1;
+            let (frame, mode, palette) = {
+                let mut core = core.lock().unwrap();
+                core.refresh((frames / BLINK_FRAMES).is_multiple_of(2));
+                (core.dd_ram.to_shared(FRAME_SEGMENT), core.video, core.palette.clone())
+            };
+            for sender in senders.iter() {
+                // The screen only wants the latest frame, so a full
+                // channel is skipped rather than waited on, except for
+                // the last frame of a program.
+                let wrapper = VideoOutWrapper{ data: frame.clone(), mode, palette: palette.clone() };
+                if finished {
+                    let _ = sender.send_timeout(wrapper, FRAME_INTERVAL * 6);
+                }
+                else {
+                    let _ = sender.try_send(wrapper);
+                }
+            }
+            last_frame = Instant::now();
+        }
+    }
+}
+
+impl GpuCore{
+    /// Executes one instruction. A fault stops the GPU and is returned.
+    fn step(&mut self) -> Result<(), CpuFault> {
+        if self.idle || self.fault.is_some() {
+            return Ok(());
+        }
+
+        let result = match self.dd_ram.try_get(PROGRAM_SEGMENT, self.program_counter as usize) {
+            Ok(word) => { self.compute(word as u32) }
+            Err(error) => { Err(CpuFault::from_memory_error(error, self.program_counter)) }
+        };
+
+        if let Err(fault) = result {
+            self.fault = Some(fault);
+        }
+        result
+    }
+
+    /// Executes a single instruction and advances the program counter.
+    fn compute(&mut self, instruction: u32) -> Result<(), CpuFault>{
+        let op = get_bits(instruction, 4, 28);
+        let ra: usize = get_bits(instruction, 3, 6) as usize;
+        let rb: usize = get_bits(instruction, 3, 3) as usize;
+        let rc: usize = get_bits(instruction, 3, 0) as usize;
+        let rl: usize = get_bits(instruction, 3, 25) as usize;
+        let lval = get_bits(instruction, 25, 0);
+
+        let mut jumped = false;
+
+        match get_opcode(op){
+            GPU_Opcode::NOP => {}
+            GPU_Opcode::Clear => { self.clear(); }
+            GPU_Opcode::MovC => { self.move_cursor(rb, rc); }
+            GPU_Opcode::Print => { self.print(rc); }
+            GPU_Opcode::Jump => {
+                self.jump(self.registers[rc]);
+                jumped = true;
+            }
+            GPU_Opcode::Run => {
+                self.load_program(rb, rc)?;
+                jumped = true;
+            }
+            GPU_Opcode::MapSeg => { self.map_seg(rb, rc)?; }
+            GPU_Opcode::UMapSeg => { self.unmap_seg(rb)?; }
+            GPU_Opcode::Load => { self.load(ra, rb, rc)?; }
+            GPU_Opcode::Store => { self.store(ra, rb, rc)?; }
+            GPU_Opcode::CMov => { self.cmov(ra, rb, rc); }
+            GPU_Opcode::Add => { self.add(ra, rb, rc); }
+            GPU_Opcode::Mul => { self.mul(ra, rb, rc); }
+            GPU_Opcode::Div => { self.div(ra, rb, rc)?; }
+            GPU_Opcode::NAND => { self.nand(ra, rb, rc); }
+            GPU_Opcode::MovI => { self.load_val(rl, lval as u64); }
+            GPU_Opcode::INVALID => {
+                return Err(CpuFault::InvalidOpcode{
+                    pc: self.program_counter,
+                    instruction
+                });
+            }
+        }
+        if !jumped {
+            self.program_counter += 1;
+        }
+        Ok(())
+    }
+
+    fn memory_fault(&self, error: crate::ram::MemoryError) -> CpuFault {
+        CpuFault::from_memory_error(error, self.program_counter)
+    }
+
+    /// Remaps the framebuffer and text cells to the size `video` needs,
+    /// which clears them.
+    fn set_video_mode(&mut self, video: VideoMode){
+        let (columns, rows) = (video.width / CELL_WIDTH, video.height / CELL_HEIGHT);
+        for (segment, length) in [(FRAME_SEGMENT, video.frame_words()), (TEXT_SEGMENT, columns * rows)] {
+            let ram = &mut self.dd_ram;
+            let new = ram.request_segment(length).unwrap();
+            ram.duplicate_segment(new, segment);
+            ram.release_segment(new).unwrap();
+        }
+        self.video = video;
+        self.columns = columns;
+        self.rows = rows;
+        self.cursor = (0, 0);
+    }
+
+    fn clear(&mut self){
+        for segment in [FRAME_SEGMENT, TEXT_SEGMENT] {
+            let length = self.dd_ram.segment_length(segment).unwrap_or(0);
+            for i in 0..length {
+                self.dd_ram.set(segment, i, 0);
+            }
+        }
+        self.cursor = (0, 0);
+    }
 
-        let delta_max = 1000000000_u128 / (self.clock_speed as u128);
+    fn move_cursor(&mut self, rb: usize, rc: usize){
+        self.cursor = (self.registers[rb] as usize, self.registers[rc] as usize);
+    }
 
-        let mut clock = quanta::Clock::new();
-        let mut timer = clock.raw();
+    fn print(&mut self, rc: usize){
+        let value = self.registers[rc];
+        self.put_char(value);
+    }
 
-        self.program_counter = 0;
-        'run: loop{
-            let delta = clock.raw() - timer;
+    /// Writes a character into the text cell at the cursor and moves the
+    /// cursor along, wrapping at the end of each row and scrolling at the
+    /// bottom of the screen. A character without colours of its own gets
+    /// the current attribute. `\n`, `\r` and backspace move the cursor.
+    fn put_char(&mut self, value: u64){
+        if self.columns == 0 || self.rows == 0 {
+            return;
+        }
+        let (mut x, mut y) = self.cursor;
+        match value{
+            0x0a => { x = 0; y = y.saturating_add(1); }
+            0x0d => { x = 0; }
+            0x08 => {
+                if x > 0 {
+                    x = x.min(self.columns) - 1;
+                    self.set_cell(x, y.min(self.rows - 1), 0);
+                }
+            }
+            _ => {
+                if x >= self.columns {
+                    x = 0;
+                    // `MovC` can put the cursor anywhere, so this may be far
+                    // past the last row already.
+                    y = y.saturating_add(1);
+                }
+                y = self.scroll(y);
 
-            if delta > delta_max as u64 {
+                let cell = if value >> 8 == 0 { value | self.attribute << 8 } else { value };
+                self.set_cell(x, y, cell);
+                x += 1;
+            }
+        }
+        self.cursor = (x, self.scroll(y));
+    }
 
-                for sender in self.senders.iter(){
-                    let c = self.dd_ram.to_vec(1);
-                    let b: Vec<&[u64]> = c.chunks_exact(3).collect();
+    fn set_cell(&mut self, x: usize, y: usize, cell: u64){
+        self.dd_ram.set(TEXT_SEGMENT, y * self.columns + x, cell);
+    }
 
-                    let mut buffer: Vec<[u8; 3]> = Vec::new();
+    /// Scrolls the text up until row `y` is on the screen, returning the
+    /// row it ends up on.
+    fn scroll(&mut self, y: usize) -> usize{
+        if y < self.rows {
+            return y;
+        }
+        let lines = (y - self.rows + 1).min(self.rows);
+        let cells = self.dd_ram.to_vec(TEXT_SEGMENT);
+        let shift = lines * self.columns;
+        for (i, cell) in cells.iter().enumerate() {
+            let value = if i + shift < cells.len() { cells[i + shift] } else { 0 };
+            if value != *cell {
+                self.dd_ram.set(TEXT_SEGMENT, i, value);
+            }
+        }
+        self.rows - 1
+    }
 
-                    for i in 0..b.len(){
-                        let x = [b[i][0] as u8, b[i][1] as u8, b[i][2] as u8];
-                        buffer.push(x);
-                    }
+    /// Draws the text cells into the framebuffer if in text mode.
+    /// `blink_on` shows blinking characters and the cursor. Nothing is
+    /// drawn if the console hasn't changed since it was last drawn.
+    fn refresh(&mut self, blink_on: bool){
+        if self.mode != MODE_TEXT || !self.dd_ram.is_mapped(FRAME_SEGMENT) {
+            return;
+        }
+        let drawing = DrawnText{
+            cells: self.dd_ram.to_shared(TEXT_SEGMENT),
+            frame: self.dd_ram.to_shared(FRAME_SEGMENT),
+            cursor: self.cursor,
+            blink_on,
+            palette: self.palette.clone()
+        };
+        if self.drawn.as_ref().is_some_and(|drawn| drawn.is_same(&drawing)) {
+            return;
+        }
+        // Let go of the old frame first, so writing it doesn't copy it.
+        self.drawn = None;
+        let DrawnText{ cells, frame, .. } = drawing;
+        let mut frame = Arc::unwrap_or_clone(frame);
 
-                    sender.send(buffer).unwrap();
+        for (i, cell) in cells.iter().enumerate() {
+            let (column, row) = (i % self.columns, i / self.columns);
+            let character = DChar::from_cell(*cell);
+            let (mut foreground, background) = character.colour_indices();
+            if character.blink && !blink_on {
+                foreground = background;
+            }
+            let mut glyph = character.glyph();
+            if blink_on && self.cursor == (column, row) {
+                glyph[CELL_HEIGHT - 1] = 0xff;
+            }
+
+            for (y, line) in glyph.iter().enumerate() {
+                for x in 0..CELL_WIDTH {
+                    let colour = if line >> x & 1 == 1 { foreground } else { background };
+                    self.video.set_pixel(&mut frame, column * CELL_WIDTH + x, row * CELL_HEIGHT + y, colour, &self.palette);
                 }
+            }
+        }
+
+        for (i, new) in frame.iter().enumerate() {
+            if self.dd_ram.try_get(FRAME_SEGMENT, i) != Ok(*new) {
+                self.dd_ram.set(FRAME_SEGMENT, i, *new);
+            }
+        }
+        self.drawn = Some(DrawnText{
+            cells,
+            frame: self.dd_ram.to_shared(FRAME_SEGMENT),
+            cursor: self.cursor,
+            blink_on,
+            palette: self.palette.clone()
+        });
+    }
+
+    /// Jumping to the current instruction finishes the program.
+    fn jump(&mut self, target: u64){
+        if target == self.program_counter {
+            self.idle = true;
+        }
+        self.program_counter = target;
+    }
+
+    fn load_program(&mut self, rb: usize, rc: usize) -> Result<(), CpuFault>{
+        let vb = self.registers[rb] as usize;
+        let vc = self.registers[rc];
+
+        if vb != PROGRAM_SEGMENT {
+            self.dd_ram.try_duplicate_segment(vb, PROGRAM_SEGMENT)
+                .map_err(|e| self.memory_fault(e))?;
+        }
+        self.program_counter = vc;
+        Ok(())
+    }
 
-                timer = clock.raw();
-            }
-
-        }
-    }
-    // 
-    // pub fn build_instruction(&self, op: GPU_Opcode, ra: usize, rb: usize, rc: usize) -> u32{
-    //     if op as u32 > GPU_Opcode::LV as u32 ||
-    //         ra >= self.registers.len() ||
-    //         rb >= self.registers.len() ||
-    //         rc >= self.registers.len()
-    //     {
-    //         panic!("Bad instruction parameters!");
-    //     }
-    //     ((op as u32) << 28) | (ra << 6) as u32 | (rb << 3) as u32 | rc as u32
-    // }
-    // 
-    // pub fn instruction(&mut self, op: GPU_Opcode, ra: usize, rb: usize, rc: usize){
-    //     let inst = self.build_instruction(op, ra, rb, rc);
-    //     self.compute(inst);
-    // }
-    // 
-    // pub fn build_lv_inst(&self, rl: usize, lv: u32) -> u32{
-    //     if !check_fits(lv as u64, 25){
-    //         panic!("value won't fit into 25 bits!")
-    //     }
-    //     ((GPU_Opcode::LV as u32) << 28) | (rl << 25) as u32 | (lv) as u32
-    // }
-    // 
-    // pub fn lv_instruction(&mut self, rl: usize, lv: u32){
-    //     let inst = self.build_lv_inst(rl, lv);
-    //     self.compute(inst);
-    // }
-    // 
-    // pub fn disassemble(&self, instruction: u32) -> String {
-    //     let op = get_bits(instruction, 4, 28);
-    //     let ra: usize = get_bits(instruction, 3, 6) as usize;
-    //     let rb: usize = get_bits(instruction, 3, 3) as usize;
-    //     let rc: usize = get_bits(instruction, 3, 0) as usize;
-    //     let rl: usize = get_bits(instruction, 3, 25) as usize;
-    //     let lval = get_bits(instruction, 25, 0);
-    // 
-    //     //println!("{:x}", instruction);
-    // 
-    //     if op == GPU_Opcode::LV as u32 {
-    //         format!("{:?} {} {}", get_opcode(op), rl, lval)
-    //     }
-    //     else if op != GPU_Opcode::INVALID as u32{
-    //         format!("{:?} {} {} {}", get_opcode(op), ra, rb, rc)
-    //     }
-    //     else{
-    //         format!("Junk or invalid operation.")
-    //     }
-    // }
-    // 
-    // pub fn compute(&mut self, instruction: u32){
-    //     let op = get_bits(instruction, 4, 28);
-    //     let ra: usize = get_bits(instruction, 3, 6) as usize;
-    //     let rb: usize = get_bits(instruction, 3, 3) as usize;
-    //     let rc: usize = get_bits(instruction, 3, 0) as usize;
-    //     let rl: usize = get_bits(instruction, 3, 25) as usize;
-    //     let lval = get_bits(instruction, 25, 0);
-    // 
-    //     match op{
-    //         opcode =>{
-    //             if opcode == GPU_Opcode::CMov as u32{
-    //                 self.cmov(ra, rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::Load as u32{
-    //                 self.load(ra, rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::Store as u32{
-    //                 self.store(ra, rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::Add as u32{
-    //                 self.add(ra, rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::Mul as u32{
-    //                 self.mul(ra, rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::Div as u32{
-    //                 self.div(ra, rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::NAND as u32{
-    //                 self.nand(ra, rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::MapSeg as u32{
-    //                 self.map_seg(rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::UMapSeg as u32{
-    //                 self.unmap_seg(rc);
-    //             }
-    //             else if opcode == GPU_Opcode::Run as u32{
-    //                 self.load_program(rb, rc);
-    //             }
-    //             else if opcode == GPU_Opcode::MovI as u32{
-    //                 self.load_val(rl, lval as u64);
-    //             }
-    //             else{
-    //                 panic!("Bad Opcode! No operation found!");
-    //             }
-    //         }
-    //     }
-    // }
-    // 
-    // fn cmov(&mut self, ra: usize, rb: usize, rc: usize){
-    //     if self.registers[rc] != 0{
-    //         let b = self.registers[rb];
-    //         self.registers[ra] = b;
-    //     }
-    // }
-    // 
-    // fn load(&mut self, ra: usize, rb: usize, rc: usize){
-    //     let seg_id = self.registers[rb];
-    //     let index = self.registers[rc];
-    //     self.registers[ra] = self.ddram.get(seg_id as usize, index as usize) as u64;
-    // }
-    // 
-    // fn store(&mut self, ra: usize, rb: usize, rc: usize){
-    //     let seg_id = self.registers[ra] as usize;
-    //     let index = self.registers[rb] as usize;
-    //     let value = self.registers[rc];
-    //     self.ddram.set(seg_id, index, value);
-    // }
-    // 
-    // fn add(&mut self, ra: usize, rb: usize, rc: usize){
-    //     let vb = self.registers[rb];
-    //     let vc = self.registers[rc];
-    //     self.registers[ra] = ((vb as u128 + vc as u128) % (1_u128 << 64)) as u64;
-    // }
-    // 
-    // fn mul(&mut self, ra: usize, rb: usize, rc: usize){
-    //     let vb = self.registers[rb];
-    //     let vc = self.registers[rc];
-    //     self.registers[ra] = ((vb as u128 * vc as u128) % (1_u128 << 64)) as u64;
-    // }
-    // 
-    // fn div(&mut self, ra: usize, rb: usize, rc: usize){
-    //     let vb = self.registers[rb];
-    //     let vc = self.registers[rc];
-    //     if vc == 0 {
-    //         panic!("Division by 0!");
-    //     }
-    //     self.registers[ra] = ((vb as u128 / vc as u128) % (1_u128 << 64)) as u64;
-    // }
-    // 
-    // fn nand(&mut self, ra: usize, rb: usize, rc: usize){
-    //     let vb = self.registers[rb];
-    //     let vc = self.registers[rc];
-    //     self.registers[ra] = !(vb & vc);
-    // }
-    // 
-    // fn map_seg(&mut self, rb: usize, rc: usize){
-    //     let word_count = self.registers[rc];
-    //     let seg_id = self.ddram.request_segment(word_count as usize) as u64;
-    //     self.registers[rb] = seg_id;
-    // }
-    // 
-    // fn unmap_seg(&mut self, rc: usize){
-    //     let seg_id = self.registers[rc];
-    //     self.ddram.release_segment(seg_id as usize);
-    // }
-    // 
-    // fn load_program(&mut self, rb: usize, rc :usize){
-    //     let vb = self.registers[rb];
-    //     let vc = self.registers[rc];
-    // 
-    //     if vb == 0{
-    //         self.program_counter = vc - 1;
-    //         return
-    //     }
-    //     
-    //     self.ddram.duplicate_segment(vb as usize, 0);
-    //     self.program_counter = vc - 1;
-    // }
-    // 
-    // fn load_val(&mut self, rl: usize, lv: u64){
-    //     self.registers[rl] = lv;
-    // }
-    // 
-    // pub fn print_state(&self){
-    //     println!("Registers:");
-    // 
-    //     for i in 0..self.registers.len(){
-    //         println!("R[{}]: {}", i, self.registers[i] as i32)
-    //     }
-    //     println!("PC: {}", self.program_counter)
-    // }
-    // 
-    // pub fn interrupt(&mut self, _signal: u128){
-    // 
-    // }
+    fn map_seg(&mut self, rb: usize, rc: usize) -> Result<(), CpuFault>{
+        let word_count = self.registers[rc] as usize;
+        let seg_id = self.dd_ram.request_segment(word_count)
+            .map_err(|e| self.memory_fault(e))? as u64;
+        self.registers[rb] = seg_id;
+        Ok(())
+    }
+
+    /// The program, framebuffer and text segments can't be unmapped.
+    fn unmap_seg(&mut self, rb: usize) -> Result<(), CpuFault>{
+        let seg_id = self.registers[rb];
+        if seg_id as usize <= TEXT_SEGMENT {
+            return Err(CpuFault::BadSegment{ pc: self.program_counter, segment: seg_id });
+        }
+        self.dd_ram.release_segment(seg_id as usize)
+            .map_err(|e| self.memory_fault(e))
+    }
+
+    fn load(&mut self, ra: usize, rb: usize, rc: usize) -> Result<(), CpuFault>{
+        let seg_id = self.registers[rb];
+        let index = self.registers[rc];
+        self.registers[ra] = self.dd_ram.try_get(seg_id as usize, index as usize)
+            .map_err(|e| self.memory_fault(e))? & self.word_mask;
+        Ok(())
+    }
+
+    fn store(&mut self, ra: usize, rb: usize, rc: usize) -> Result<(), CpuFault>{
+        let seg_id = self.registers[ra] as usize;
+        let index = self.registers[rb] as usize;
+        let value = self.registers[rc];
+        self.dd_ram.try_set(seg_id, index, value)
+            .map_err(|e| self.memory_fault(e))
+    }
+
+    fn cmov(&mut self, ra: usize, rb: usize, rc: usize){
+        if self.registers[rc] != 0{
+            let b = self.registers[rb];
+            self.registers[ra] = b;
+        }
+    }
+
+    fn add(&mut self, ra: usize, rb: usize, rc: usize){
+        let vb = self.registers[rb];
+        let vc = self.registers[rc];
+        self.registers[ra] = vb.wrapping_add(vc) & self.word_mask;
+    }
+
+    fn mul(&mut self, ra: usize, rb: usize, rc: usize){
+        let vb = self.registers[rb];
+        let vc = self.registers[rc];
+        self.registers[ra] = vb.wrapping_mul(vc) & self.word_mask;
+    }
+
+    fn div(&mut self, ra: usize, rb: usize, rc: usize) -> Result<(), CpuFault>{
+        let vb = self.registers[rb];
+        let vc = self.registers[rc];
+        if vc == 0 {
+            return Err(CpuFault::DivideByZero{ pc: self.program_counter });
+        }
+        self.registers[ra] = (vb / vc) & self.word_mask;
+        Ok(())
+    }
+
+    fn nand(&mut self, ra: usize, rb: usize, rc: usize){
+        let vb = self.registers[rb];
+        let vc = self.registers[rc];
+        self.registers[ra] = !(vb & vc) & self.word_mask;
+    }
+
+    fn load_val(&mut self, rl: usize, lv: u64){
+        self.registers[rl] = lv & self.word_mask;
+    }
+}
+
+impl Drop for GPU {
+    fn drop(&mut self) {
+        self.power_off();
+    }
+}
+
+impl MachinePart for GPU {
+    fn name(&self) -> &str {
+        "gpu"
+    }
+
+    fn ports(&self) -> Range<u64> {
+        GPU_PORTS
+    }
+
+    fn port_read(&mut self, port: u64) -> u64 {
+        match port{
+            PORT_CONTROL => { self.status() }
+            PORT_ENTRY => { self.pc() }
+            PORT_REGISTER => { self.selected_register as u64 }
+            PORT_REGISTER_DATA => {
+                self.core().registers.get(self.selected_register).copied().unwrap_or(u64::MAX)
+            }
+            PORT_SEGMENT => { self.selected_segment as u64 }
+            PORT_INDEX => { self.index as u64 }
+            PORT_DATA => {
+                let value = self.core().dd_ram.try_get(self.selected_segment, self.index).unwrap_or(u64::MAX);
+                self.index += 1;
+                value
+            }
+            PORT_WAIT => {
+                self.wait();
+                self.status()
+            }
+            PORT_MODE => { self.mode() }
+            PORT_ATTRIBUTE => { self.core().attribute }
+            PORT_FORMAT => { self.video_mode().format.code() }
+            PORT_RESOLUTION => {
+                let (width, height) = self.resolution();
+                (width << 16 | height) as u64
+            }
+            PORT_PALETTE_INDEX => { self.core().palette_index as u64 }
+            PORT_PALETTE_DATA => {
+                let mut core = self.core();
+                let [red, green, blue] = core.palette[core.palette_index];
+                core.palette_index = (core.palette_index + 1) % PALETTE_SIZE;
+                (red as u64) << 16 | (green as u64) << 8 | blue as u64
+            }
+            _ => { u64::MAX }
+        }
+    }
+
+    fn port_write(&mut self, port: u64, value: u64) {
+        match port{
+            PORT_CONTROL => {
+                if value == 0 {
+                    self.stop();
+                }
+                else {
+                    self.start();
+                }
+            }
+            PORT_ENTRY => { self.set_pc(value); }
+            PORT_REGISTER => { self.selected_register = value as usize; }
+            PORT_REGISTER_DATA => { self.set_register(self.selected_register, value); }
+            PORT_SEGMENT => {
+                self.selected_segment = value as usize;
+                self.index = 0;
+            }
+            PORT_INDEX => { self.index = value as usize; }
+            PORT_DATA => {
+                let (segment, index) = (self.selected_segment, self.index);
+                let _ = self.core().dd_ram.try_set(segment, index, value);
+                self.index += 1;
+            }
+            PORT_MAP => {
+                let segment = self.core().dd_ram.request_segment(value as usize);
+                self.selected_segment = segment.unwrap_or(usize::MAX);
+                self.index = 0;
+            }
+            PORT_UNMAP if value as usize > TEXT_SEGMENT => {
+                let _ = self.core().dd_ram.release_segment(value as usize);
+            }
+            PORT_PROGRAM => {
+                let _ = self.core().dd_ram.try_duplicate_segment(value as usize, PROGRAM_SEGMENT);
+            }
+            PORT_MODE => { self.set_mode(value); }
+            PORT_CONSOLE => { self.print(value); }
+            PORT_ATTRIBUTE => { self.core().attribute = value & 0x3ff; }
+            PORT_FORMAT => {
+                if let Some(format) = PixelFormat::from_code(value) {
+                    let (width, height) = self.resolution();
+                    self.set_video_mode(format, width, height);
+                }
+            }
+            PORT_RESOLUTION => {
+                let format = self.video_mode().format;
+                self.set_video_mode(format, (value >> 16 & 0xffff) as usize, (value & 0xffff) as usize);
+            }
+            PORT_PALETTE_INDEX => { self.core().palette_index = value as usize % PALETTE_SIZE; }
+            PORT_PALETTE_DATA => {
+                let mut core = self.core();
+                let index = core.palette_index;
+                core.palette[index] = [(value >> 16) as u8, (value >> 8) as u8, value as u8];
+                core.palette_index = (index + 1) % PALETTE_SIZE;
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.power_off();
+        self.selected_register = 0;
+        self.selected_segment = 0;
+        self.index = 0;
+        let video = VideoMode::new(PixelFormat::Rgb, self.x_size, self.y_size, self.register_width);
+        let mut core = self.core();
+        core.set_video_mode(video);
+        core.palette = default_palette();
+        core.palette_index = 0;
+        core.registers.iter_mut().for_each(|r| *r = 0);
+        core.program_counter = 0;
+        core.idle = true;
+        core.fault = None;
+        core.drawn = None;
+        core.mode = MODE_GRAPHICS;
+        core.attribute = 0;
+        core.clear();
+    }
+
+    fn snapshot(&self) -> Vec<StateValue> {
+        let core = self.core();
+        let mut state: Vec<StateValue> = core.registers.iter()
+            .enumerate()
+            .map(|(i, r)| (format!("r{i}"), *r))
+            .collect();
+        state.push((String::from("pc"), core.program_counter));
+        state
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crossbeam::channel::bounded;
+    use crate::cpu::CpuFault;
+    use crate::display_data_helper::{DChar, PALETTE};
+    use crate::gpu::{GPU, GPU_Opcode, build_instruction, build_lv_inst, FRAME_SEGMENT, TEXT_SEGMENT, MODE_TEXT, PORT_ATTRIBUTE,
+        PORT_FORMAT, PORT_RESOLUTION, PORT_PALETTE_INDEX, PORT_PALETTE_DATA};
+    use crate::MachinePart::MachinePart;
+    use crate::scheduler::UNLIMITED;
+    use crate::video_mode::{PixelFormat, MONO_COLOURS};
+
+    fn run_to_end(gpu: &mut GPU) {
+        for _ in 0..1000 {
+            if gpu.is_idle() || gpu.fault().is_some() {
+                return;
+            }
+            let _ = gpu.step();
+        }
+        panic!("GPU program did not finish");
+    }
+
+    #[test]
+    fn arithmetic_and_memory_test(){
+        let mut gpu = GPU::new(UNLIMITED, 32, 8, 16, 16);
+        gpu.load_program(&[
+            build_lv_inst(1, 6),
+            build_lv_inst(2, 7),
+            build_instruction(GPU_Opcode::Mul, 3, 1, 2),
+            build_instruction(GPU_Opcode::MapSeg, 0, 4, 1),
+            build_instruction(GPU_Opcode::Store, 4, 1, 3), // index 6 of a 6 word segment
+        ]);
+        run_to_end(&mut gpu);
+        assert_eq!(gpu.registers()[3], 42);
+        assert!(matches!(gpu.fault(), Some(CpuFault::OutOfBounds{ pc: 4, .. })));
+
+        gpu.load_program(&[
+            build_lv_inst(1, 1),
+            build_lv_inst(2, 2),
+            build_lv_inst(3, 4),          // jump target
+            build_instruction(GPU_Opcode::Jump, 0, 0, 3),
+            build_instruction(GPU_Opcode::Store, 1, 0, 2), // m[1][0] = 2
+            build_instruction(GPU_Opcode::Load, 5, 1, 0),
+            build_instruction(GPU_Opcode::NAND, 6, 5, 5),
+            build_lv_inst(7, 8),
+            build_instruction(GPU_Opcode::Jump, 0, 0, 7),
+        ]);
+        run_to_end(&mut gpu);
+        assert_eq!(gpu.fault(), None);
+        assert_eq!(gpu.pc(), 8);
+        let registers = gpu.registers();
+        assert_eq!(registers[5], 2);
+        assert_eq!(registers[6], 0xffff_fffd);
+        assert_eq!(gpu.segment(FRAME_SEGMENT).unwrap()[0], 2);
+    }
+
+    #[test]
+    fn print_and_clear_test(){
+        // 16x16 pixels is two rows of two cells.
+        let mut gpu = GPU::new(UNLIMITED, 32, 8, 16, 16);
+        gpu.load_program(&[
+            build_lv_inst(1, 1),
+            build_lv_inst(2, 'A' as u32),
+            build_instruction(GPU_Opcode::MovC, 0, 1, 0),  // column 1, row 0
+            build_instruction(GPU_Opcode::Print, 0, 0, 2),
+            build_instruction(GPU_Opcode::Print, 0, 0, 2), // wraps to the next row
+            build_lv_inst(7, 6),
+            build_instruction(GPU_Opcode::Jump, 0, 0, 7),
+        ]);
+        run_to_end(&mut gpu);
+        assert_eq!(gpu.segment(TEXT_SEGMENT).unwrap(), vec![0, 'A' as u64, 'A' as u64, 0]);
+        assert_eq!(gpu.cursor(), (1, 1));
+
+        gpu.load_program(&[
+            build_instruction(GPU_Opcode::Clear, 0, 0, 0),
+            build_lv_inst(7, 2),
+            build_instruction(GPU_Opcode::Jump, 0, 0, 7),
+        ]);
+        run_to_end(&mut gpu);
+        assert_eq!(gpu.segment(TEXT_SEGMENT).unwrap(), vec![0; 4]);
+        assert_eq!(gpu.cursor(), (0, 0));
+    }
+
+    #[test]
+    fn thread_test(){
+        let (sender, receiver) = bounded(1);
+        let mut gpu = GPU::new(UNLIMITED, 32, 8, 2, 1);
+        gpu.add_signaler(sender);
+        gpu.load_program(&[
+            build_lv_inst(1, 1),
+            build_lv_inst(2, 200),
+            build_instruction(GPU_Opcode::Store, 1, 0, 2),
+            build_lv_inst(7, 4),
+            build_instruction(GPU_Opcode::Jump, 0, 0, 7),
+        ]);
+        gpu.start();
+
+        // The last frame is sent when the program finishes.
+        let frame = loop {
+            let frame = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            if frame.data[0] != 0 {
+                break frame;
+            }
+        };
+        assert_eq!(*frame.data, vec![200, 0, 0, 0, 0, 0]);
+        gpu.stop();
+        assert!(gpu.is_idle());
+        assert!(!gpu.is_running());
+    }
+
+    #[test]
+    fn console_scroll_test(){
+        let mut gpu = GPU::new(UNLIMITED, 32, 8, 16, 16);
+        for c in "AB\nCDE".bytes() {
+            gpu.print(c as u64);
+        }
+        // E didn't fit, so the text scrolled up a row.
+        let cells: Vec<u64> = "CDE".bytes().map(|c| c as u64).chain([0]).collect();
+        assert_eq!(gpu.segment(TEXT_SEGMENT).unwrap(), cells);
+        assert_eq!(gpu.cursor(), (1, 1));
+
+        gpu.print(0x08);
+        gpu.print('\r' as u64);
+        gpu.print('F' as u64);
+        assert_eq!(gpu.segment(TEXT_SEGMENT).unwrap(), vec!['C' as u64, 'D' as u64, 'F' as u64, 0]);
+        assert_eq!(gpu.cursor(), (1, 1));
+    }
+
+    #[test]
+    fn print_past_last_row_test(){
+        // 16x16 pixels is two rows of two cells.
+        let mut gpu = GPU::new(UNLIMITED, 64, 8, 16, 16);
+        gpu.load_program(&[
+            build_instruction(GPU_Opcode::NAND, 3, 0, 0), // r3 = u64::MAX
+            build_lv_inst(1, 1),
+            build_lv_inst(2, 'A' as u32),
+            build_lv_inst(4, '\n' as u32),
+            build_instruction(GPU_Opcode::Print, 0, 0, 2),
+            build_instruction(GPU_Opcode::MovC, 0, 3, 3),  // wraps from the last column
+            build_instruction(GPU_Opcode::Print, 0, 0, 2),
+            build_instruction(GPU_Opcode::MovC, 0, 1, 3),
+            build_instruction(GPU_Opcode::Print, 0, 0, 4),
+            build_instruction(GPU_Opcode::Print, 0, 0, 2),
+            build_lv_inst(7, 11),
+            build_instruction(GPU_Opcode::Jump, 0, 0, 7),
+        ]);
+        run_to_end(&mut gpu);
+        assert_eq!(gpu.fault(), None);
+        // Each move past the last row scrolled the whole screen away.
+        assert_eq!(gpu.segment(TEXT_SEGMENT).unwrap(), vec![0, 0, 'A' as u64, 0]);
+        assert_eq!(gpu.cursor(), (1, 1));
+    }
+
+    #[test]
+    fn text_mode_test(){
+        let mut gpu = GPU::new(UNLIMITED, 32, 8, 16, 8);
+        gpu.set_mode(MODE_TEXT);
+        gpu.port_write(PORT_ATTRIBUTE, 0x1f); // white on blue
+        gpu.print('A' as u64);
+        gpu.refresh();
+
+        let frame = gpu.segment(FRAME_SEGMENT).unwrap();
+        let pixel = |x: usize, y: usize| [frame[(y * 16 + x) * 3], frame[(y * 16 + x) * 3 + 1], frame[(y * 16 + x) * 3 + 2]].map(|c| c as u8);
+
+        let glyph = DChar::from_cell('A' as u64).glyph();
+        for (y, line) in glyph.iter().enumerate() {
+            for x in 0..8 {
+                let expected = if line >> x & 1 == 1 { PALETTE[15] } else { PALETTE[1] };
+                assert_eq!(pixel(x, y), expected);
+            }
+        }
+        // The cursor is an underline in the next, empty cell.
+        assert_eq!(pixel(8, 0), PALETTE[0]);
+        assert_eq!(pixel(8, 7), PALETTE[7]);
+    }
+
+    #[test]
+    fn unchanged_text_test(){
+        let mut gpu = GPU::new(UNLIMITED, 32, 8, 16, 8);
+        gpu.set_mode(MODE_TEXT);
+        gpu.print('A' as u64);
+        gpu.refresh();
+        let drawn = gpu.core().dd_ram.to_shared(FRAME_SEGMENT);
+
+        // Nothing changed, so the frame isn't copied or written.
+        gpu.refresh();
+        assert!(Arc::ptr_eq(&drawn, &gpu.core().dd_ram.to_shared(FRAME_SEGMENT)));
+
+        gpu.print('B' as u64);
+        gpu.refresh();
+        assert!(!Arc::ptr_eq(&drawn, &gpu.core().dd_ram.to_shared(FRAME_SEGMENT)));
+        assert_ne!(*drawn, gpu.segment(FRAME_SEGMENT).unwrap());
+    }
+
+    #[test]
+    fn video_mode_test(){
+        let mut gpu = GPU::new(UNLIMITED, 32, 8, 16, 8);
+        gpu.port_write(PORT_FORMAT, PixelFormat::Mono.code());
+        assert_eq!(gpu.port_read(PORT_FORMAT), PixelFormat::Mono.code());
+        assert_eq!(gpu.segment(FRAME_SEGMENT).unwrap().len(), 4);
+
+        gpu.port_write(PORT_RESOLUTION, 32 << 16 | 16);
+        assert_eq!(gpu.resolution(), (32, 16));
+        assert_eq!(gpu.port_read(PORT_RESOLUTION), 32 << 16 | 16);
+        assert_eq!(gpu.segment(FRAME_SEGMENT).unwrap().len(), 16);
+        assert_eq!(gpu.segment(TEXT_SEGMENT).unwrap().len(), 8);
+
+        // Bad modes are ignored.
+        gpu.port_write(PORT_RESOLUTION, 0);
+        gpu.port_write(PORT_FORMAT, 9);
+        assert_eq!(gpu.video_mode().format, PixelFormat::Mono);
+        assert_eq!(gpu.resolution(), (32, 16));
+        assert!(!GPU::new(UNLIMITED, 16, 8, 16, 8).set_video_mode(PixelFormat::PackedRgb, 16, 8));
+
+        gpu.port_write(PORT_PALETTE_INDEX, 5);
+        gpu.port_write(PORT_PALETTE_DATA, 0x102030);
+        gpu.port_write(PORT_PALETTE_DATA, 0x405060);
+        assert_eq!(gpu.port_read(PORT_PALETTE_INDEX), 7);
+        assert_eq!(&gpu.palette()[5..7], &[[0x10, 0x20, 0x30], [0x40, 0x50, 0x60]]);
+
+        gpu.reset();
+        assert_eq!(gpu.video_mode().format, PixelFormat::Rgb);
+        assert_eq!(gpu.resolution(), (16, 8));
+        assert_eq!(gpu.segment(FRAME_SEGMENT).unwrap().len(), 16 * 8 * 3);
+    }
+
+    #[test]
+    fn mono_text_mode_test(){
+        let mut gpu = GPU::new(UNLIMITED, 32, 8, 8, 8);
+        assert!(gpu.set_video_mode(PixelFormat::Mono, 8, 8));
+        gpu.set_mode(MODE_TEXT);
+        gpu.print('A' as u64);
+        gpu.refresh();
+
+        let video = gpu.video_mode();
+        let colours = video.decode(&gpu.segment(FRAME_SEGMENT).unwrap(), &gpu.palette()).unwrap();
+        let glyph = DChar::from_cell('A' as u64).glyph();
+        for (y, line) in glyph.iter().enumerate() {
+            for x in 0..8 {
+                let set = line >> x & 1 == 1;
+                assert_eq!(colours[y * 8 + x], MONO_COLOURS[set as usize]);
+            }
+        }
+    }
 }
@@ -1,5 +1,7 @@
+use std::any::Any;
 use std::fs::File;
 use std::io::{Read, Write};
+use crate::MachinePart::{MachinePart, StateValue};
 
 pub struct HardDrive{
     image: Vec<u8>,
@@ -8,7 +10,7 @@ pub struct HardDrive{
 
 impl HardDrive{
 
-    pub fn from_file(name: Option<&str>) -> Result<HardDrive, &str>{
+    pub fn from_file(name: Option<&str>) -> Result<HardDrive, std::io::Error>{
         let mut file = File::open(
             match name{
                 None => { "maindisk.wmiso" },
@@ -16,12 +18,12 @@ impl HardDrive{
                     filename
                 }
             }
-        ).unwrap();
+        )?;
         
         
         
         let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
+        file.read_to_end(&mut buffer)?;
         let size = buffer.len();
         
         //println!("{}", size);
@@ -33,6 +35,15 @@ impl HardDrive{
         )
     }
 
+    /// Creates a drive holding `image` without touching the filesystem.
+    pub fn from_bytes(image: Vec<u8>) -> HardDrive{
+        let size = image.len();
+        HardDrive{
+            image,
+            size
+        }
+    }
+
     pub fn new(name: Option<&str>, size: usize) -> HardDrive{
         let mut file = match File::create(
             match name{
@@ -51,7 +62,7 @@ impl HardDrive{
         };
         let buffer = vec![0u8; size];
 
-        file.write(&*buffer).expect("Could not write bytes!");
+        file.write_all(&buffer).expect("Could not write bytes!");
 
         HardDrive{
             image: buffer,
@@ -66,4 +77,22 @@ impl HardDrive{
     pub fn load_segment(&mut self, addr: usize, length: usize) -> Vec<u8>{
         (&self.image)[addr..(addr+length)].to_vec()
     }
+}
+
+impl MachinePart for HardDrive {
+    fn name(&self) -> &str {
+        "storage"
+    }
+    
+    fn snapshot(&self) -> Vec<StateValue> {
+        vec![(String::from("size"), self.size as u64)]
+    }
+    
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
 }
\ No newline at end of file
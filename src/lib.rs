@@ -4,7 +4,16 @@ pub mod ram;
 pub mod harddrive;
 pub mod machine;
 pub mod cpu;
+#[cfg(feature = "sdl")]
 pub mod screen;
 pub mod gpu;
 pub mod display_data_helper;
-pub mod MachinePart;
\ No newline at end of file
+pub mod video_mode;
+pub mod MachinePart;
+pub mod scheduler;
+pub mod config;
+pub mod disassembler;
+pub mod assembler;
+pub mod debugger;
+pub mod gdbstub;
+pub mod trace;
\ No newline at end of file
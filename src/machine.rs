@@ -1,80 +1,337 @@
 
-use std::thread;
+use std::sync::Arc;
 use crossbeam::channel::Sender;
-use sdl2::libc::system;
-use sdl2::mouse::SystemCursor::No;
-use crate::cpu::{CPU, CPU_Opcode, get_bits};
+use crate::cpu::{CPU, CpuFault};
 use crate::gpu::{GPU};
 use crate::harddrive::HardDrive;
-use crate::MachinePart::MachinePart;
+use crate::MachinePart::{Bus, MachinePart};
 use crate::ram::RAM;
+use crate::scheduler::SchedulerReport;
+use crate::config::{ConfigError, MachineConfig};
+use crate::video_mode::VideoMode;
 
+/// A computer assembled from its parts.
+///
+/// The machine owns every part in a registry of boxed `MachinePart`s, so
+/// devices defined outside the crate can be plugged in next to the built-in
+/// CPU, GPU, RAM and drives. The CPU is lent the RAM by `&mut` for each
+/// instruction while every other part is ticked alongside it, and parts
+/// running on other threads, like the screen, only ever receive owned copies
+/// of memory through channels.
 pub struct Machine{
-    cpu: Option<CPU>,
-    gpu: Option<GPU>,
-    ram: Option<RAM>,
-    storage: Vec<HardDrive>,
-    parts: Option<MachineWrapper>,
+    parts: Vec<Box<dyn MachinePart>>,
+    um_mode: bool,
 }
 
-pub struct MachineWrapper{
-    pub cpu: *mut CPU,
-    pub gpu: *mut GPU,
-    pub ram: *mut RAM,
-    pub storage: *mut Vec<HardDrive>
+/// A frame sent to the screen: the framebuffer, and the video mode and
+/// palette to decode it with. The framebuffer is shared with the GPU's
+/// memory until the GPU next writes to it.
+pub struct VideoOutWrapper{
+    pub data: Arc<Vec<u64>>,
+    pub mode: VideoMode,
+    pub palette: Vec<[u8; 3]>
 }
 
-pub struct VideoOutWrapper{
-    pub data: Vec<u64>
+/// The parts other than the CPU and its RAM, as the CPU sees them.
+struct Devices<'a>(Vec<&'a mut Box<dyn MachinePart>>);
+
+impl Bus for Devices<'_> {
+    fn port_read(&mut self, port: u64) -> Option<u64> {
+        self.0.iter_mut()
+            .find(|part| part.ports().contains(&port))
+            .map(|part| part.port_read(port))
+    }
+
+    fn port_write(&mut self, port: u64, value: u64) -> bool {
+        match self.0.iter_mut().find(|part| part.ports().contains(&port)) {
+            Some(part) => {
+                part.port_write(port, value);
+                true
+            }
+            None => { false }
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        for part in self.0.iter_mut() {
+            part.tick(cycles);
+        }
+    }
+}
+
+/// An error building a `Machine`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// The machine cannot boot without these parts.
+    MissingParts(Vec<&'static str>)
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            BuildError::MissingParts(missing) => {
+                write!(f, "missing parts: {}", missing.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// An error loading or running the program on a `Machine`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MachineError {
+    /// The machine lacks a part it needs, e.g. one made with
+    /// `Machine::new` that was never given a CPU.
+    Build(BuildError),
+    /// The CPU faulted and halted.
+    Fault(CpuFault)
+}
+
+impl From<BuildError> for MachineError {
+    fn from(error: BuildError) -> Self {
+        MachineError::Build(error)
+    }
+}
+
+impl From<CpuFault> for MachineError {
+    fn from(fault: CpuFault) -> Self {
+        MachineError::Fault(fault)
+    }
+}
+
+impl std::fmt::Display for MachineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            MachineError::Build(error) => { write!(f, "{}", error) }
+            MachineError::Fault(fault) => { write!(f, "{}", fault) }
+        }
+    }
+}
+
+impl std::error::Error for MachineError {}
+
+/// Assembles a `Machine` and checks it can boot.
+///
+/// ```no_run
+/// use warch::cpu::CPU;
+/// use warch::harddrive::HardDrive;
+/// use warch::machine::MachineBuilder;
+/// use warch::ram::RAM;
+/// use warch::scheduler::UNLIMITED;
+///
+/// let mut machine = MachineBuilder::new()
+///     .cpu(CPU::new(UNLIMITED, 32, 8))
+///     .ram(RAM::new())
+///     .drive(HardDrive::from_bytes(vec![0x70, 0, 0, 0]))
+///     .um_mode(true)
+///     .build()
+///     .unwrap();
+/// machine.boot().unwrap();
+/// ```
+pub struct MachineBuilder{
+    machine: Machine,
+    senders: Vec<Sender<VideoOutWrapper>>
+}
+
+impl Default for MachineBuilder{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl MachineBuilder{
+    pub fn new() -> Self{
+        MachineBuilder{
+            machine: Machine::new(),
+            senders: Vec::new()
+        }
+    }
+    
+    pub fn cpu(self, cpu: CPU) -> Self{
+        self.part(cpu)
+    }
+    
+    pub fn ram(self, ram: RAM) -> Self{
+        self.part(ram)
+    }
+    
+    pub fn gpu(self, gpu: GPU) -> Self{
+        self.part(gpu)
+    }
+    
+    /// Attaches a drive. The first drive is the one the machine boots from.
+    pub fn drive(self, drive: HardDrive) -> Self{
+        self.part(drive)
+    }
+    
+    /// Plugs in any other part.
+    pub fn part<P: MachinePart>(mut self, part: P) -> Self{
+        self.machine.insert(part);
+        self
+    }
+    
+    /// See `Machine::set_um_mode`.
+    pub fn um_mode(mut self, enabled: bool) -> Self{
+        self.machine.set_um_mode(enabled);
+        self
+    }
+    
+    /// Sends the GPU's framebuffer to `sender`.
+    pub fn video_sender(mut self, sender: Sender<VideoOutWrapper>) -> Self{
+        self.senders.push(sender);
+        self
+    }
+    
+    /// Checks every part needed to boot is present and returns the machine.
+    pub fn build(mut self) -> Result<Machine, BuildError>{
+        self.machine.check_parts()?;
+        
+        for sender in self.senders {
+            self.machine.add_sender(sender);
+        }
+        Ok(self.machine)
+    }
 }
 
-unsafe impl Send for VideoOutWrapper{}
 
 impl Machine{
 
     pub fn new() -> Self{
         Self{
-            cpu: None,
-            gpu: None,
-            ram: None,
-            storage: Vec::new(),
-            parts: None
+            parts: Vec::new(),
+            um_mode: false
+        }
+    }
+    
+    /// Builds a machine with the parts described by `config`.
+    ///
+    /// The configuration is validated first, so a bad value is reported
+    /// with its key instead of panicking while the parts are built.
+    pub fn from_config(config: &MachineConfig) -> Result<Self, ConfigError>{
+        config.validate()?;
+        
+        let mut machine = Machine::new();
+        machine.insert(RAM::with_capacity(config.ram.capacity, config.ram.max_segments));
+        machine.insert(CPU::new(config.cpu.clock_speed, config.cpu.register_width, config.cpu.register_count));
+        machine.insert(GPU::new(config.gpu.clock_speed, config.gpu.register_width, config.gpu.register_count, config.gpu.width, config.gpu.height));
+        
+        for (i, drive) in config.drives.iter().enumerate() {
+            let hd = HardDrive::from_file(Some(&drive.image)).map_err(|error| ConfigError::Invalid{
+                key: format!("drive[{i}].image"),
+                reason: error.to_string()
+            })?;
+            machine.insert(hd);
         }
+        
+        Ok(machine)
+    }
+    
+    /// Switches the machine to Universal Machine compatibility.
+    ///
+    /// In this mode no GPU is required and `boot` maps only the program
+    /// segment `m[0]`, so standard UM images run unmodified.
+    pub fn set_um_mode(&mut self, enabled: bool) {
+        self.um_mode = enabled;
     }
 
-    pub fn insert(&mut self, part: MachinePart) {
-        match part{
-            MachinePart::RAM(ram) => {
-                self.ram = Some(ram);
+    /// Plugs a part into the machine.
+    ///
+    /// The machine runs the first CPU on the first RAM and loads its program
+    /// from the first drive; every other part is ticked as the CPU runs.
+    pub fn insert<P: MachinePart>(&mut self, part: P) {
+        self.parts.push(Box::new(part));
+    }
+    
+    /// All parts, in the order they were inserted.
+    pub fn parts(&self) -> impl Iterator<Item = &dyn MachinePart> {
+        self.parts.iter().map(|part| part.as_ref())
+    }
+    
+    /// The first part of type `P`.
+    pub fn part<P: MachinePart>(&self) -> Option<&P> {
+        self.parts.iter().find_map(|part| part.as_any().downcast_ref::<P>())
+    }
+    
+    /// The first part of type `P`, mutably.
+    pub fn part_mut<P: MachinePart>(&mut self) -> Option<&mut P> {
+        self.parts.iter_mut().find_map(|part| part.as_any_mut().downcast_mut::<P>())
+    }
+    
+    /// The first part with the given name.
+    pub fn find(&mut self, name: &str) -> Option<&mut dyn MachinePart> {
+        self.parts.iter_mut().find(|part| part.name() == name).map(|part| part.as_mut())
+    }
+    
+    /// Resets every part to its power-on state.
+    pub fn reset(&mut self) {
+        self.parts.iter_mut().for_each(|part| part.reset());
+    }
+    
+    /// Reads the I/O port `port` from the part that handles it.
+    pub fn port_read(&mut self, port: u64) -> Option<u64> {
+        self.parts.iter_mut()
+            .find(|part| part.ports().contains(&port))
+            .map(|part| part.port_read(port))
+    }
+    
+    /// Writes `value` to the I/O port `port` on the part that handles it.
+    /// Returns false if no part handles the port.
+    pub fn port_write(&mut self, port: u64, value: u64) -> bool {
+        match self.parts.iter_mut().find(|part| part.ports().contains(&port)) {
+            Some(part) => {
+                part.port_write(port, value);
+                true
             }
-            MachinePart::CPU(cpu) => {
-                self.cpu = Some(cpu);
+            None => { false }
+        }
+    }
+    
+    /// Splits the registry into the CPU, the RAM it runs on, and every
+    /// other part.
+    fn split_parts(&mut self) -> (Option<&mut CPU>, Option<&mut RAM>, Vec<&mut Box<dyn MachinePart>>) {
+        let mut cpu = None;
+        let mut ram = None;
+        let mut others = Vec::new();
+        
+        for part in self.parts.iter_mut() {
+            if cpu.is_none() && part.as_any().is::<CPU>() {
+                cpu = part.as_any_mut().downcast_mut::<CPU>();
             }
-            MachinePart::GPU(gpu) => {
-                self.gpu = Some(gpu);
+            else if ram.is_none() && part.as_any().is::<RAM>() {
+                ram = part.as_any_mut().downcast_mut::<RAM>();
             }
-            MachinePart::Storage(drive) => {
-                self.storage.push(drive);
+            else {
+                others.push(part);
             }
         }
-    }
-
-    pub fn disassemble(&mut self, input: Option<&str>){
-        
-        self.storage.push(HardDrive::from_file(input).unwrap());
         
-        // set up m[0]
-        let size = self.storage[0].get_byte_length().clone();
-        let mut prog = self.storage[0].load_segment(0, size);
+        (cpu, ram, others)
+    }
     
-        let mut i = 0;
-        for bytes in prog.chunks_exact_mut(8){
-            let word = Self::get_32bit_instruction(bytes);
-            println!("{:x}: {:x}", i, word);
-            let dasm = self.cpu.as_mut().unwrap().disassemble(word);
-            println!("{dasm}");
-            i += 4;
-        };
+    /// The CPU, the RAM it runs on and the other parts, or the parts missing
+    /// to run a program.
+    fn running_parts(&mut self) -> Result<(&mut CPU, &mut RAM, Devices<'_>), BuildError> {
+        match self.split_parts() {
+            (Some(cpu), Some(ram), others) => { Ok((cpu, ram, Devices(others))) }
+            (cpu, ram, _) => {
+                let mut missing = Vec::new();
+                if cpu.is_none() {
+                    missing.push("CPU");
+                }
+                if ram.is_none() {
+                    missing.push("RAM");
+                }
+                Err(BuildError::MissingParts(missing))
+            }
+        }
+    }
+    
+    /// The program image on the first drive.
+    fn program(&mut self) -> Result<Vec<u8>, BuildError> {
+        let drive = self.part_mut::<HardDrive>().ok_or(BuildError::MissingParts(vec!["STORAGE"]))?;
+        let size = drive.get_byte_length();
+        Ok(drive.load_segment(0, size))
     }
 
     fn get_32bit_instruction(bytes: &mut [u8]) -> u32{
@@ -110,89 +367,289 @@ impl Machine{
     // }
 
     pub fn get_ram(&mut self) -> &mut RAM{
-        self.ram.as_mut().unwrap()
+        self.part_mut::<RAM>().unwrap()
     }
-    pub fn get_storage(&mut self) -> &mut Vec<HardDrive>{
-        &mut self.storage
+    pub fn get_storage(&mut self) -> Vec<&mut HardDrive>{
+        self.parts.iter_mut()
+            .filter_map(|part| part.as_any_mut().downcast_mut::<HardDrive>())
+            .collect()
     }
     
-    pub fn power_on_self_test(&mut self) {
-        let mut flag = false;
-        if self.cpu.is_none() {
-            eprintln!("NO CPU FOUND");
-            flag = true;
+    /// Checks that every part needed to boot is present.
+    fn check_parts(&self) -> Result<(), BuildError> {
+        let mut missing = Vec::new();
+        if self.part::<CPU>().is_none() {
+            missing.push("CPU");
         }
-        if self.gpu.is_none(){
-            eprintln!("NO GPU FOUND");
-            flag = true;
+        if self.part::<GPU>().is_none() && !self.um_mode{
+            missing.push("GPU");
         }
-        if self.ram.is_none() {
-            eprintln!("NO RAM FOUND");
-            flag = true;
+        if self.part::<RAM>().is_none() {
+            missing.push("RAM");
         }
-        if self.storage.len() == 0{
-            eprintln!("NO STORAGE FOUND");
-            flag = true;
-        }
-
-        if flag{
-            std::process::exit(0);
+        if self.part::<HardDrive>().is_none(){
+            missing.push("STORAGE");
         }
         
-        let t = MachineWrapper{
-            cpu: self.cpu.as_mut().unwrap() as *mut CPU,
-            gpu: self.gpu.as_mut().unwrap() as *mut GPU,
-            ram: self.ram.as_mut().unwrap() as *mut RAM,
-            storage: self.storage.as_mut() as *mut Vec<HardDrive>
-        };
-        
-        self.parts = Some(t);
+        if missing.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(BuildError::MissingParts(missing))
+        }
+    }
+    
+    pub fn power_on_self_test(&mut self) -> Result<(), BuildError> {
+        if let Err(BuildError::MissingParts(missing)) = self.check_parts() {
+            for part in missing.iter() {
+                eprintln!("NO {part} FOUND");
+            }
+            return Err(BuildError::MissingParts(missing));
+        }
         
-        println!("BEEP!")
+        if !self.um_mode {
+            println!("BEEP!")
+        }
+        Ok(())
     }
     
+    /// Sends the GPU's framebuffer to `sender` whenever the GPU runs.
     pub fn add_sender(&mut self, sender: Sender<VideoOutWrapper>){
-        self.cpu.as_mut().unwrap().add_signaler(sender);
+        if let Some(gpu) = self.part_mut::<GPU>() {
+            gpu.add_signaler(sender);
+        }
     }
     
-    pub fn boot(&mut self, input: Option<&str>) {
+    /// Loads the program image into `m[0]` and runs the CPU until it halts.
+    ///
+    /// Returns the fault that halted the machine, if any.
+    pub fn boot(&mut self) -> Result<(), MachineError> {
+        self.load()?;
+        self.run()
+    }
+    
+    /// Runs the loaded program from the start until it halts.
+    pub fn run(&mut self) -> Result<(), MachineError> {
+        let (cpu, ram, mut devices) = self.running_parts()?;
+        cpu.run_on(ram, &mut devices).map_err(MachineError::Fault)
+    }
+    
+    /// Maps `m[0]` and loads the program image, leaving the CPU at the first
+    /// instruction without running it. The framebuffer lives in the GPU, so
+    /// the first segment the program maps is `m[1]`.
+    ///
+    /// Every segment mapped before is released first, so loading again
+    /// starts the program over with the same memory.
+    pub fn load(&mut self) -> Result<(), MachineError> {
         //self.gpu.unwrap().init(b1, a2);
         
-        self.storage.push(HardDrive::from_file(input).unwrap());
-        //let t = self.parts.as_ref().unwrap();
+        // set up the instructions that will go into m[0]
+        let mut prog = self.program()?;
         
-        // GPU
-        let m = self.parts.as_ref().unwrap();
+        let (cpu, ram, _) = self.running_parts()?;
+        ram.reset();
+
+        // make the original segment m[0] for program. It is mapped directly
+        // rather than through the CPU's registers, which may be too narrow
+        // to hold its size.
+        let memory_fault = |error| CpuFault::from_memory_error(error, 0);
+        ram.request_segment(prog.len() / 4).map_err(memory_fault)?;
+
+        //println!("Test1");
+        // load the instructions into m[0]
+        for (i, bytes) in prog.chunks_exact_mut(4).enumerate() {
+            let word = Self::get_32bit_instruction(bytes);
+            ram.try_set(0, i, word as u64).map_err(memory_fault)?;
+        };
         
-        unsafe {
-            // set up the instructions that will go into m[0]
-            let size = self.storage[0].get_byte_length().clone();
-            let mut prog = self.storage[0].load_segment(0, size);
+        cpu.set_pc(0);
+        Ok(())
+    }
+    
+    /// Executes the instruction at the program counter and clocks the other
+    /// parts with the cycles it took.
+    pub fn step(&mut self) -> Result<(), MachineError> {
+        let (cpu, ram, mut devices) = self.running_parts()?;
+        let cycles = cpu.cycles();
+        let result = cpu.step_on(ram, &mut devices);
+        devices.tick(cpu.cycles() - cycles);
+        result.map_err(MachineError::Fault)
+    }
+    
+    /// Speed statistics of the CPU's last run.
+    pub fn report(&self) -> Option<SchedulerReport>{
+        self.part::<CPU>().and_then(|cpu| cpu.report())
+    }
+    
+    pub fn halt(&mut self){
+        self.part_mut::<CPU>().unwrap().halt();
+    }
+}
+#[cfg(test)]
+mod tests{
+    use std::any::Any;
+    use std::ops::Range;
+    use crate::assembler::assemble;
+    use crate::cpu::CPU;
+    use crate::gpu::{GPU, FRAME_SEGMENT, PORT_CONSOLE, STATUS_IDLE, TEXT_SEGMENT};
+    use crate::harddrive::HardDrive;
+    use crate::machine::{BuildError, Machine, MachineBuilder, MachineError};
+    use crate::MachinePart::MachinePart;
+    use crate::ram::RAM;
+    use crate::scheduler::UNLIMITED;
 
-            // make the original segment m[0] for program
-            (*m.cpu).lv_instruction(m, 0, (prog.len() / 4) as u32);
-            (*m.cpu).instruction(m, CPU_Opcode::MapSeg, 0, 0, 0);
-            
-            
+    struct Timer{
+        count: u64
+    }
+
+    impl MachinePart for Timer{
+        fn name(&self) -> &str { "timer" }
+        fn reset(&mut self) { self.count = 0; }
+        fn tick(&mut self, cycles: u64) { self.count += cycles; }
+        fn ports(&self) -> Range<u64> { 0x40..0x42 }
+        fn port_read(&mut self, _port: u64) -> u64 { self.count }
+        fn port_write(&mut self, _port: u64, value: u64) { self.count = value; }
+        fn as_any(&self) -> &dyn Any { self }
+        fn as_any_mut(&mut self) -> &mut dyn Any { self }
+    }
+
+    #[test]
+    fn builder_missing_parts_test(){
+        let result = MachineBuilder::new()
+            .cpu(CPU::new(1, 32, 8))
+            .build();
+        
+        assert_eq!(result.err(), Some(BuildError::MissingParts(vec!["GPU", "RAM", "STORAGE"])));
+    }
+    
+    #[test]
+    fn unbuilt_missing_parts_test(){
+        let missing = |parts: Vec<&'static str>| Err(MachineError::Build(BuildError::MissingParts(parts)));
+        let mut machine = Machine::new();
+        assert_eq!(machine.load(), missing(vec!["STORAGE"]));
+        assert_eq!(machine.run(), missing(vec!["CPU", "RAM"]));
+        assert_eq!(machine.step(), missing(vec!["CPU", "RAM"]));
+        
+        machine.insert(HardDrive::from_bytes(vec![0x70, 0, 0, 0]));
+        machine.insert(RAM::new());
+        assert_eq!(machine.load(), missing(vec!["CPU"]));
+    }
+    
+    #[test]
+    fn load_twice_test(){
+        // map r2, r1 with r1 = 3, then halt
+        let mut machine = MachineBuilder::new()
+            .cpu(CPU::new(UNLIMITED, 32, 8))
+            .ram(RAM::new())
+            .drive(HardDrive::from_bytes(vec![0xd2, 0, 0, 3, 0x80, 0, 0, 0x11, 0x70, 0, 0, 0]))
+            .um_mode(true)
+            .build()
+            .unwrap();
+        
+        assert_eq!(machine.boot(), Ok(()));
+        assert_eq!(machine.get_ram().used_words(), 6);
+        assert_eq!(machine.load(), Ok(()));
+        assert_eq!(machine.get_ram().used_words(), 3);
+        assert!(!machine.get_ram().is_mapped(1));
+        assert_eq!(machine.load(), Ok(()));
+        assert_eq!(machine.get_ram().segment_length(0), Ok(3));
+        assert_eq!(machine.get_ram().get(0, 2), 0x70000000);
+    }
+    
+    #[test]
+    fn builder_boot_test(){
+        // halt
+        let mut machine = MachineBuilder::new()
+            .cpu(CPU::new(UNLIMITED, 32, 8))
+            .ram(RAM::new())
+            .drive(HardDrive::from_bytes(vec![0x70, 0, 0, 0]))
+            .um_mode(true)
+            .build()
+            .unwrap();
+        
+        assert_eq!(machine.boot(), Ok(()));
+    }
 
-            // // make the original segment m[1] for video out
-            (*m.cpu).lv_instruction(m, 0, (100 * 100 * 3) as u32);
-            (*m.cpu).instruction(m, CPU_Opcode::MapSeg, 0, 0, 0);
+    #[test]
+    fn boot_every_width_test(){
+        // More words than an 8 or 16-bit register can count: no-ops, then halt.
+        let words = (1 << 16) + 1;
+        let mut program = vec![0u8; words * 4];
+        program[(words - 1) * 4] = 0x70;
 
-            //println!("Test1");
-            // load the instructions into m[0]
-            let mut i = 0;
-            for bytes in prog.chunks_exact_mut(4) {
-                let word = Self::get_32bit_instruction(bytes);
-                (*m.ram).set(0, i, word as u64);
-                i += 1;
-            };
+        for width in [8, 16, 32, 64] {
+            let mut machine = MachineBuilder::new()
+                .cpu(CPU::new(UNLIMITED, width, 8))
+                .gpu(GPU::new(UNLIMITED, 32, 8, 8, 8))
+                .ram(RAM::new())
+                .drive(HardDrive::from_bytes(program.clone()))
+                .build()
+                .unwrap();
 
-            (*m.cpu).run(m);
+            assert_eq!(machine.boot(), Ok(()), "{width}-bit CPU");
+            assert_eq!(machine.get_ram().segment_length(0), Ok(words));
+            // The framebuffer isn't mapped into the CPU's memory.
+            assert!(machine.get_ram().segment_length(1).is_err());
+            assert!(machine.part::<CPU>().unwrap().is_halted());
         }
     }
-    
-    pub fn halt(&mut self){
-        self.cpu.as_mut().unwrap().halt();
+
+    #[test]
+    fn gpu_demo_test(){
+        let program = assemble(include_str!("../programs/gpu_demo.asm")).unwrap();
+        let mut machine = MachineBuilder::new()
+            .cpu(CPU::new(UNLIMITED, 32, 8))
+            .gpu(GPU::new(UNLIMITED, 32, 8, 100, 100))
+            .ram(RAM::new())
+            .drive(HardDrive::from_bytes(program.to_bytes()))
+            .build()
+            .unwrap();
+
+        assert_eq!(machine.boot(), Ok(()));
+        // The CPU read the GPU's status after waiting for it.
+        assert_eq!(machine.part::<CPU>().unwrap().registers()[5], STATUS_IDLE);
+
+        let gpu = machine.part::<GPU>().unwrap();
+        assert!(gpu.is_idle());
+        let frame = gpu.segment(FRAME_SEGMENT).unwrap();
+        assert_eq!(frame.len(), 30000);
+        assert!(frame.iter().enumerate().all(|(i, word)| *word == i as u64 * 7));
+    }
+
+    #[test]
+    fn console_output_test(){
+        let program = assemble("movi r1, 'H'\noutput r1\nmovi r1, 'i'\noutput r1\nhalt\n").unwrap();
+        let mut cpu = CPU::new(UNLIMITED, 32, 8);
+        cpu.set_output_port(Some(PORT_CONSOLE));
+        let mut machine = MachineBuilder::new()
+            .cpu(cpu)
+            .gpu(GPU::new(UNLIMITED, 32, 8, 16, 8))
+            .ram(RAM::new())
+            .drive(HardDrive::from_bytes(program.to_bytes()))
+            .build()
+            .unwrap();
+
+        assert_eq!(machine.boot(), Ok(()));
+        let gpu = machine.part::<GPU>().unwrap();
+        assert_eq!(gpu.segment(TEXT_SEGMENT).unwrap(), vec!['H' as u64, 'i' as u64]);
+        assert_eq!(gpu.cursor(), (2, 0));
+    }
+
+    #[test]
+    fn custom_part_test(){
+        let mut machine = Machine::new();
+        machine.insert(CPU::new(1, 32, 8));
+        machine.insert(RAM::new());
+        machine.insert(Timer{ count: 0 });
+
+        assert!(machine.part::<CPU>().is_some());
+        assert_eq!(machine.find("timer").unwrap().name(), "timer");
+
+        assert!(machine.port_write(0x41, 5));
+        assert_eq!(machine.port_read(0x40), Some(5));
+        assert_eq!(machine.port_read(0x42), None);
+
+        machine.reset();
+        assert_eq!(machine.part::<Timer>().unwrap().count, 0);
     }
 }
\ No newline at end of file
@@ -1,17 +1,28 @@
 use std::sync::mpsc::{Receiver, RecvError, Sender, TryRecvError};
+#[cfg(feature = "sdl")]
 use std::thread;
+use std::thread::JoinHandle;
+#[cfg(feature = "sdl")]
 use std::time::Duration;
+#[cfg(feature = "sdl")]
 use sdl2::event::Event;
+#[cfg(feature = "sdl")]
 use sdl2::keyboard::Keycode;
-use warch::machine::{Machine, VideoOutWrapper};
+use warch::machine::{Machine, MachineBuilder, MachineError, VideoOutWrapper};
+#[cfg(feature = "sdl")]
 use warch::screen::Screen;
 use clap::Parser;
-use crossbeam::channel::{bounded, unbounded};
+use crossbeam::channel::{bounded, unbounded, Receiver as VideoReceiver};
 use warch::cpu::CPU;
-use warch::gpu::{GPU};
 use warch::harddrive::HardDrive;
-use warch::MachinePart::MachinePart;
 use warch::ram::RAM;
+use warch::scheduler::UNLIMITED;
+use warch::config::{DriveConfig, MachineConfig, ScreenConfig};
+use warch::disassembler::Disassembly;
+use warch::debugger::Debugger;
+use warch::gdbstub::GdbStub;
+use warch::trace::{TraceWriter, recorded_input, recorded_port_reads};
+use warch::gpu::{GPU, MODE_TEXT, PORT_CONSOLE};
 
 /// First computer specs:
 /// CPU: Intel 8088
@@ -24,51 +35,158 @@ struct Args{
     #[arg(short = 'i', long = "input")]
     input: Option<String>,
 
+    /// Print a listing of the input image instead of running it.
     #[arg(short = 'd', long = "disassemble", required = false)]
     disassemble: bool,
 
+    /// With -d, print assembler source that assembles back into the image.
+    #[arg(long = "source", required = false, requires = "disassemble")]
+    source: bool,
+
+    /// Run a standard Universal Machine image: 8 x 32-bit registers, only m[0], stdin/stdout I/O.
+    #[arg(long = "um", required = false)]
+    um: bool,
+
+    /// Run the CPU as fast as possible instead of at its clock speed.
+    #[arg(long = "unlimited", required = false)]
+    unlimited: bool,
+
+    /// Print the achieved and target MIPS when the machine halts.
+    #[arg(long = "stats", required = false)]
+    stats: bool,
+
+    /// Run without a screen window. Always on when built without the "sdl" feature.
+    #[arg(long = "headless", required = false)]
+    headless: bool,
+
+    /// Show the program's output on the screen's text console instead of stdout.
+    #[arg(long = "console", required = false)]
+    console: bool,
+
+    /// Start in the interactive debugger instead of running the program.
+    #[arg(long = "debug", required = false)]
+    debug: bool,
+
+    /// Wait for gdb to attach on ADDRESS (a port, host:port or unix:<path>) instead of running the program.
+    #[arg(long = "gdb", value_name = "ADDRESS", conflicts_with = "debug")]
+    gdb: Option<String>,
+
+    /// Record every executed instruction to a trace file (see warch-trace).
+    #[arg(long = "trace", value_name = "FILE")]
+    trace: Option<String>,
+
+    /// Feed the input bytes and port reads recorded in a trace file to the program
+    /// instead of stdin and the devices.
+    #[arg(long = "replay", value_name = "FILE")]
+    replay: Option<String>,
+
+    /// Build the machine from a TOML configuration file.
+    #[arg(short = 'c', long = "config")]
+    config: Option<String>,
+
 }
 
 fn main() {
     let args = Args::parse();
-    /*
-    TODO:
-    Figure out how a configuration file would look
-    For now though, worry about making a rudimentary system.
-    */
     // 
     // if std::env::args().len() > 2 {
     //     panic!("Usage: TODO");
     // }
 
-    let file: Option<String> = args.input;
-    //let dasm: bool = args.disassemble;
+    let file: Option<String> = args.input.clone();
+    
+    if args.disassemble {
+        disassemble(file.as_deref(), args.source);
+        return;
+    }
+    
+    if args.um {
+        run_um(file.as_deref(), &args);
+        return;
+    }
+    
+    let mut config = match args.config.as_deref() {
+        Some(path) => {
+            MachineConfig::from_file(path).unwrap_or_else(|e| {
+                eprintln!("{path}: {e}");
+                std::process::exit(1);
+            })
+        }
+        None => { MachineConfig::default() }
+    };
+    
+    if args.unlimited {
+        config.cpu.clock_speed = UNLIMITED;
+    }
+    // The input file, or the default disk if nothing else is attached, is
+    // the boot drive.
+    match file {
+        Some(image) => { config.drives.insert(0, DriveConfig{ image }) },
+        None if config.drives.is_empty() => { config.drives.push(DriveConfig{ image: String::from("maindisk.wmiso") }) },
+        None => {}
+    }
     
     // IDEA SPACE
     // ----------------
     
     let (sender, receiver) = bounded::<VideoOutWrapper>(1);
     
-    let ram = MachinePart::RAM(RAM::new());
-    let cpu = MachinePart::CPU(CPU::new(10000000000, 32, 16));
-    let hd = MachinePart::Storage(HardDrive::from_file(file.as_deref()).unwrap());
-    let gpu = MachinePart::GPU(GPU::new(200, 32, 8, 100, 100));
+    let mut machine = Machine::from_config(&config).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
 
     // ----------------
 
-    let mut machine: Machine = Machine::new();
-
-    machine.insert(ram);
-    machine.insert(cpu);
-    machine.insert(gpu);
-    machine.insert(hd);
-
-    machine.add_sender(sender);
+    // Headless machines still keep their framebuffer in the GPU, it just
+    // isn't sent anywhere.
+    let headless = args.headless || cfg!(not(feature = "sdl"));
+    let mut screen_thread = None;
+    
+    if !headless {
+        machine.add_sender(sender);
+        let (x_size, y_size) = (config.gpu.width as u32, config.gpu.height as u32);
+        screen_thread = spawn_screen(config.screen.clone(), x_size, y_size, receiver);
+    }
+    
+    if args.console {
+        if let Some(gpu) = machine.part_mut::<GPU>() {
+            gpu.set_mode(MODE_TEXT);
+        }
+        if let Some(cpu) = machine.part_mut::<CPU>() {
+            cpu.set_output_port(Some(PORT_CONSOLE));
+        }
+    }
+    
+    if machine.power_on_self_test().is_err() {
+        std::process::exit(1);
+    }
+    // The CPU has already printed a diagnostic for the fault that halted it.
+    let result = start(&mut machine, &args);
+    
+    if args.stats {
+        print_report(&machine);
+    }
+    
+    
     
-    let screen_thread = thread::spawn(move || unsafe {
+    if let Some(screen_thread) = screen_thread {
+        screen_thread.join().unwrap();
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
+}
+
+/// Opens the screen window on its own thread.
+#[cfg(feature = "sdl")]
+fn spawn_screen(screen_config: ScreenConfig, x_size: u32, y_size: u32, receiver: VideoReceiver<VideoOutWrapper>) -> Option<JoinHandle<()>> {
+    Some(thread::spawn(move || {
         let sdl_context = sdl2::init().unwrap();
 
-        let mut screen = Screen::new(8, 6, 100, 100, 255, &sdl_context, receiver);
+        let canvas = Screen::open_window(screen_config.pixel_width, screen_config.pixel_height, x_size, y_size, &sdl_context);
+        let texture_creator = canvas.texture_creator();
+        let mut screen = Screen::new(screen_config.pixel_width, screen_config.pixel_height, x_size, y_size, canvas, &texture_creator, receiver);
 
         let mut event_pump = sdl_context.event_pump().unwrap();
 
@@ -89,73 +207,104 @@ fn main() {
             pc += 1;
             thread::sleep(Duration::new(0, 1_000_000_000u32 / 120));
         }
+    }))
+}
+
+/// Without SDL there is no screen to open.
+#[cfg(not(feature = "sdl"))]
+fn spawn_screen(_screen_config: ScreenConfig, _x_size: u32, _y_size: u32, _receiver: VideoReceiver<VideoOutWrapper>) -> Option<JoinHandle<()>> {
+    None
+}
+
+/// Prints a listing, or re-assemblable source, of a program image.
+fn disassemble(file: Option<&str>, source: bool) {
+    let mut drive = HardDrive::from_file(file).unwrap_or_else(|e| {
+        eprintln!("{}: {e}", file.unwrap_or("maindisk.wmiso"));
+        std::process::exit(1);
     });
+    let size = drive.get_byte_length();
+    let disassembly = Disassembly::from_bytes(&drive.load_segment(0, size));
     
-    machine.power_on_self_test();
-    machine.boot(file.as_deref());
-    
+    if source {
+        print!("{}", disassembly.source());
+    }
+    else {
+        print!("{}", disassembly.listing());
+    }
+}
+
+/// Loads the program and runs it, or hands the machine to the debugger or
+/// gdb, recording or replaying a trace if asked to.
+fn start(machine: &mut Machine, args: &Args) -> Result<(), MachineError> {
+    machine.load()?;
     
+    let cpu = machine.part_mut::<CPU>().unwrap();
+    if let Some(path) = args.replay.as_deref() {
+        let recorded = recorded_input(path).and_then(|input| Ok((input, recorded_port_reads(path)?)));
+        let (input, port_reads) = recorded.unwrap_or_else(|e| {
+            eprintln!("{path}: {e}");
+            std::process::exit(1);
+        });
+        cpu.replay_input(input);
+        cpu.replay_port_reads(port_reads);
+    }
+    if let Some(path) = args.trace.as_deref() {
+        let tracer = TraceWriter::create(path, cpu).unwrap_or_else(|e| {
+            eprintln!("{path}: {e}");
+            std::process::exit(1);
+        });
+        cpu.set_tracer(Some(tracer));
+    }
     
-    screen_thread.join().unwrap();
+    let mut result = Ok(());
+    if let Some(address) = args.gdb.as_deref() {
+        if let Err(e) = GdbStub::new().listen(machine, address) {
+            eprintln!("gdb: {e}");
+        }
+    }
+    else if args.debug {
+        Debugger::new().repl(machine, std::io::stdin().lock(), std::io::stdout()).unwrap();
+    }
+    else {
+        result = machine.run();
+    }
     
-    // match dasm{
-    //     true => {
-    //         machine.disassemble(file.as_deref());
-    //     },
-    //     false => unsafe {
-    //         
-    //         // Allows for the cpu to be interrupted via the event_pump
-    //         let (sx, sdl_interrupter) = std::sync::mpsc::channel();
-    // 
-    //         let (a1, b1) = std::sync::mpsc::channel();
-    //         let (a2, b2): (Sender<Option<GpuSignal>>, Receiver<Option<GpuSignal>>) = std::sync::mpsc::channel();
-    //         
-    //         let screen_thread = thread::spawn(move|| {
-    //             let sdl_context = sdl2::init().unwrap();
-    // 
-    //             let mut signal: Option<GpuSignal> = None;
-    //             
-    //             a1.send(String::from("ready for connection")).ok();
-    //             
-    //             for received in b2{
-    //                 signal = received;
-    //             }
-    //             
-    //             let mut screen = Screen::new(8, 6, 100, 100, 255, &sdl_context);
-    // 
-    //             let mut event_pump = sdl_context.event_pump().unwrap();
-    //             
-    //             let mut pc: u64 = 0;
-    //             'running: loop{
-    //                 for event in event_pump.poll_iter(){
-    //                     match event{
-    //                         Event::Quit {..} |
-    //                         Event::KeyDown { keycode: Some(Keycode::Escape), ..} => {
-    //                             sx.send((7 << 28) as u128).ok();
-    //                             break 'running
-    //                         },
-    //                         _ => {}
-    //                     }
-    //                 }
-    //                 
-    //                 // TODO: Have to create segment[0] for GPU and give it some instructions to run.
-    //                 (*(signal.as_mut().unwrap()).signal).run(pc);
-    //                 
-    //                 screen.draw(&signal);
-    // 
-    //                 pc += 1;
-    //                 thread::sleep(Duration::new(0, 1_000_000_000u32 / 120));
-    //             }
-    //         });
-    //         
-    //         machine.add_signaler(sdl_interrupter);
-    //         
-    //         machine.boot(file.as_deref(), b1, a2);
-    //         
-    //         screen_thread.join().unwrap();
-    //     }
-    // }
-    // 
-    // 
+    if let Some(tracer) = machine.part_mut::<CPU>().unwrap().take_tracer() {
+        if let Err(e) = tracer.finish() {
+            eprintln!("{}: {e}", args.trace.as_deref().unwrap_or(""));
+        }
+    }
+    result
+}
 
-}
\ No newline at end of file
+/// Prints the CPU's speed statistics to stderr.
+fn print_report(machine: &Machine) {
+    if let Some(report) = machine.report() {
+        eprintln!("{report}");
+    }
+}
+
+/// Runs a Universal Machine image with no GPU or screen attached.
+fn run_um(file: Option<&str>, args: &Args) {
+    let drive = HardDrive::from_file(file).unwrap_or_else(|e| {
+        eprintln!("{}: {e}", file.unwrap_or("maindisk.wmiso"));
+        std::process::exit(1);
+    });
+    
+    let mut machine = MachineBuilder::new()
+        .ram(RAM::new())
+        .cpu(CPU::new(UNLIMITED, 32, 8))
+        .drive(drive)
+        .um_mode(true)
+        .build()
+        .unwrap();
+    
+    let result = start(&mut machine, args);
+    
+    if args.stats {
+        print_report(&machine);
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
+}
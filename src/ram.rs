@@ -1,49 +1,376 @@
+use std::any::Any;
+use std::sync::Arc;
+use crate::MachinePart::{MachinePart, StateValue};
+
+/// An error raised by a checked access to `RAM`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The segment id has never been mapped.
+    UnmappedSegment { segment: usize },
+    /// The index is past the end of the segment.
+    IndexOutOfRange { segment: usize, index: usize, length: usize },
+    /// The segment was mapped but has since been released.
+    SegmentFreed { segment: usize },
+    /// The segment was released while already free.
+    DoubleFree { segment: usize },
+    /// The segment may not be released, e.g. the program segment `m[0]`.
+    ReservedSegment { segment: usize },
+    /// Mapping `requested` more words would exceed the RAM's word budget.
+    OutOfMemory { requested: usize, available: usize },
+    /// Every segment id allowed by the RAM's segment limit is in use.
+    TooManySegments { limit: usize }
+}
+
+impl std::fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            MemoryError::UnmappedSegment { segment } => {
+                write!(f, "segment {} is not mapped", segment)
+            }
+            MemoryError::IndexOutOfRange { segment, index, length } => {
+                write!(f, "index {} out of range for segment {} of length {}", index, segment, length)
+            }
+            MemoryError::SegmentFreed { segment } => {
+                write!(f, "segment {} has been freed", segment)
+            }
+            MemoryError::DoubleFree { segment } => {
+                write!(f, "segment {} was freed twice", segment)
+            }
+            MemoryError::ReservedSegment { segment } => {
+                write!(f, "segment {} cannot be unmapped", segment)
+            }
+            MemoryError::OutOfMemory { requested, available } => {
+                write!(f, "out of memory: requested {} words with {} available", requested, available)
+            }
+            MemoryError::TooManySegments { limit } => {
+                write!(f, "out of memory: all {} segments are mapped", limit)
+            }
+        }
+    }
+}
+
+/// Segmented memory.
+///
+/// Each segment id is either mapped, in which case `segments[id]` holds its
+/// words, or free, in which case its storage has been dropped and the id is
+/// waiting in `free_segs` to be handed out again.
+///
+/// Every mapped word is accounted against `capacity`, and at most
+/// `max_segments` segments may be mapped at once.
+///
+/// Segments are copy-on-write: `duplicate_segment` shares the source's
+/// storage, and the words are only copied once either segment is written.
 pub struct RAM {
-    segments: Vec<Vec<u64>>,
-    free_segs: Vec<usize>
+    segments: Vec<Option<Arc<Vec<u64>>>>,
+    free_segs: Vec<usize>,
+    capacity: usize,
+    max_segments: Option<usize>,
+    used_words: usize,
+    mapped_segments: usize
 }
 
+/// The word budget of `RAM::new`: 512 MiB of 64-bit words, so a guest mapping
+/// a huge segment gets an out-of-memory fault instead of exhausting the host.
+pub const DEFAULT_CAPACITY: usize = 1 << 26;
+
 impl RAM{
+    /// Creates a RAM of `DEFAULT_CAPACITY` words with no limit on the number
+    /// of segments.
     pub fn new() -> Self{
+        Self::with_capacity(DEFAULT_CAPACITY, None)
+    }
+    
+    /// Creates a RAM holding at most `capacity` words across at most
+    /// `max_segments` mapped segments.
+    pub fn with_capacity(capacity: usize, max_segments: Option<usize>) -> Self{
         RAM{
             segments: Vec::new(),
-            free_segs: Vec::new()
+            free_segs: Vec::new(),
+            capacity,
+            max_segments,
+            used_words: 0,
+            mapped_segments: 0
         }
     }
+    
+    pub fn capacity(&self) -> usize{
+        self.capacity
+    }
+    
+    /// The number of words currently mapped.
+    pub fn used_words(&self) -> usize{
+        self.used_words
+    }
+    
+    /// The number of words that can still be mapped.
+    pub fn available_words(&self) -> usize{
+        self.capacity - self.used_words
+    }
 
-    pub fn request_segment(&mut self, size: usize) -> usize{
+    /// Maps a new zeroed segment of `size` words and returns its id.
+    pub fn request_segment(&mut self, size: usize) -> Result<usize, MemoryError>{
+        if let Some(limit) = self.max_segments {
+            if self.mapped_segments >= limit {
+                return Err(MemoryError::TooManySegments{ limit });
+            }
+        }
+        self.reserve(size)?;
+        
         let data = vec![0u64; size];
+        self.mapped_segments += 1;
 
         if !self.free_segs.is_empty(){
             let id = self.free_segs.pop().unwrap();
-            self.segments[id] = data;
-            id
+            self.segments[id] = Some(Arc::new(data));
+            Ok(id)
         }
         else {
             let id = self.segments.len();
-            self.segments.push(data);
-            id
+            self.segments.push(Some(Arc::new(data)));
+            Ok(id)
+        }
+    }
+    
+    /// Accounts `size` more words against the capacity.
+    fn reserve(&mut self, size: usize) -> Result<(), MemoryError>{
+        let available = self.available_words();
+        if size > available {
+            return Err(MemoryError::OutOfMemory{ requested: size, available });
         }
+        self.used_words += size;
+        Ok(())
     }
 
-    pub fn release_segment(&mut self, seg_id: usize){
-        self.free_segs.push(seg_id);
+    /// Unmaps a segment, dropping its storage so the id can be reused.
+    ///
+    /// Segment 0 holds the running program and can never be released.
+    pub fn release_segment(&mut self, seg_id: usize) -> Result<(), MemoryError>{
+        if seg_id == 0 {
+            return Err(MemoryError::ReservedSegment{ segment: seg_id });
+        }
+        match self.segments.get_mut(seg_id) {
+            None => {
+                Err(MemoryError::UnmappedSegment{ segment: seg_id })
+            }
+            Some(None) => {
+                Err(MemoryError::DoubleFree{ segment: seg_id })
+            }
+            Some(slot) => {
+                let freed = slot.take().map_or(0, |segment| segment.len());
+                self.used_words -= freed;
+                self.mapped_segments -= 1;
+                self.free_segs.push(seg_id);
+                Ok(())
+            }
+        }
+    }
+    
+    /// Returns true if `seg_id` currently refers to a mapped segment.
+    pub fn is_mapped(&self, seg_id: usize) -> bool{
+        matches!(self.segments.get(seg_id), Some(Some(_)))
     }
     
     pub fn duplicate_segment(&mut self, from: usize, to: usize){
-        let buffer = self.segments[from].clone();
-        self.segments[to] = buffer;
+        self.try_duplicate_segment(from, to).unwrap();
+    }
+
+    /// Returns true if `seg_id` shares its storage with another segment.
+    pub fn is_shared(&self, seg_id: usize) -> bool{
+        match self.segments.get(seg_id) {
+            Some(Some(segment)) => { Arc::strong_count(segment) > 1 }
+            _ => { false }
+        }
+    }
+
+    /// Returns the storage of the live segment `seg_id`.
+    fn shared_segment(&self, seg_id: usize) -> Result<&Arc<Vec<u64>>, MemoryError>{
+        match self.segments.get(seg_id) {
+            None => { Err(MemoryError::UnmappedSegment{ segment: seg_id }) }
+            Some(None) => { Err(MemoryError::SegmentFreed{ segment: seg_id }) }
+            Some(Some(segment)) => { Ok(segment) }
+        }
+    }
+
+    /// Returns the live segment `seg_id`.
+    fn segment(&self, seg_id: usize) -> Result<&Vec<u64>, MemoryError>{
+        Ok(self.shared_segment(seg_id)?.as_ref())
+    }
+    
+    /// Returns the live segment `seg_id` mutably, copying it first if its
+    /// storage is shared.
+    fn segment_mut(&mut self, seg_id: usize) -> Result<&mut Vec<u64>, MemoryError>{
+        match self.segments.get_mut(seg_id) {
+            None => { Err(MemoryError::UnmappedSegment{ segment: seg_id }) }
+            Some(None) => { Err(MemoryError::SegmentFreed{ segment: seg_id }) }
+            Some(Some(segment)) => { Ok(Arc::make_mut(segment)) }
+        }
+    }
+    
+    /// Checked version of `duplicate_segment`.
+    ///
+    /// The copy replaces `to`, so only the growth in its size is accounted.
+    /// No words are copied until one of the two segments is written.
+    pub fn try_duplicate_segment(&mut self, from: usize, to: usize) -> Result<(), MemoryError>{
+        let buffer = self.shared_segment(from)?.clone();
+        let old_length = self.segment(to)?.len();
+        let length = buffer.len();
+        if length > old_length {
+            self.reserve(length - old_length)?;
+        }
+        else {
+            self.used_words -= old_length - length;
+        }
+        
+        self.segments[to] = Some(buffer);
+        Ok(())
+    }
+
+    /// The number of words in the live segment `seg_id`.
+    pub fn segment_length(&self, seg_id: usize) -> Result<usize, MemoryError>{
+        Ok(self.segment(seg_id)?.len())
+    }
+
+    /// Checked version of `get`.
+    pub fn try_get(&self, seg_id: usize, index: usize) -> Result<u64, MemoryError>{
+        let segment = self.segment(seg_id)?;
+        match segment.get(index) {
+            None => { Err(MemoryError::IndexOutOfRange{ segment: seg_id, index, length: segment.len() }) }
+            Some(value) => { Ok(*value) }
+        }
+    }
+    
+    /// Checked version of `set`.
+    pub fn try_set(&mut self, seg_id: usize, index: usize, value: u64) -> Result<(), MemoryError>{
+        // Checked before `segment_mut` so a bad store never triggers a copy.
+        let length = self.segment(seg_id)?.len();
+        if index >= length {
+            return Err(MemoryError::IndexOutOfRange{ segment: seg_id, index, length });
+        }
+        self.segment_mut(seg_id)?[index] = value;
+        Ok(())
     }
 
     pub fn get(&mut self, seg_id: usize, index: usize) -> u64{
-        self.segments[seg_id][index]
+        self.segments[seg_id].as_ref().expect("segment is not mapped")[index]
     }
     
     pub fn set(&mut self, seg_id: usize, index: usize, value: u64){
-        self.segments[seg_id][index] = value;
+        let segment = self.segments[seg_id].as_mut().expect("segment is not mapped");
+        Arc::make_mut(segment)[index] = value;
     }
     
     pub fn to_vec(&self, index: usize) -> Vec<u64>{
-        self.segments[index].clone()
+        self.segments[index].as_deref().expect("segment is not mapped").clone()
+    }
+
+    /// The words of a segment as they are now, without copying them. The
+    /// segment is copied instead if it is written while they are held.
+    pub fn to_shared(&self, index: usize) -> Arc<Vec<u64>>{
+        self.segments[index].clone().expect("segment is not mapped")
+    }
+}
+
+impl MachinePart for RAM {
+    fn name(&self) -> &str {
+        "ram"
+    }
+    
+    fn reset(&mut self) {
+        self.segments.clear();
+        self.free_segs.clear();
+        self.used_words = 0;
+        self.mapped_segments = 0;
+    }
+    
+    fn snapshot(&self) -> Vec<StateValue> {
+        vec![
+            (String::from("capacity"), self.capacity as u64),
+            (String::from("used_words"), self.used_words as u64),
+            (String::from("mapped_segments"), self.mapped_segments as u64)
+        ]
+    }
+    
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::cpu::{CPU, CPU_Opcode, CpuFault, FaultKind};
+    use crate::ram::{MemoryError, RAM, DEFAULT_CAPACITY};
+
+    #[test]
+    fn checked_access_test(){
+        let mut ram = RAM::new();
+        assert_eq!(ram.request_segment(2), Ok(0));
+        assert_eq!(ram.request_segment(3), Ok(1));
+
+        assert_eq!(ram.try_set(1, 2, 9), Ok(()));
+        assert_eq!(ram.try_get(1, 2), Ok(9));
+        assert_eq!(ram.try_get(1, 3), Err(MemoryError::IndexOutOfRange{ segment: 1, index: 3, length: 3 }));
+        assert_eq!(ram.try_set(0, 2, 1), Err(MemoryError::IndexOutOfRange{ segment: 0, index: 2, length: 2 }));
+        assert_eq!(ram.try_get(7, 0), Err(MemoryError::UnmappedSegment{ segment: 7 }));
+        assert_eq!(ram.try_set(7, 0, 1), Err(MemoryError::UnmappedSegment{ segment: 7 }));
+
+        assert_eq!(ram.try_duplicate_segment(1, 0), Ok(()));
+        assert_eq!(ram.try_get(0, 2), Ok(9));
+        assert_eq!(ram.try_duplicate_segment(7, 0), Err(MemoryError::UnmappedSegment{ segment: 7 }));
+        assert_eq!(ram.try_duplicate_segment(0, 7), Err(MemoryError::UnmappedSegment{ segment: 7 }));
+    }
+
+    #[test]
+    fn release_test(){
+        let mut ram = RAM::new();
+        ram.request_segment(1).unwrap();
+        let segment = ram.request_segment(4).unwrap();
+
+        assert_eq!(ram.release_segment(0), Err(MemoryError::ReservedSegment{ segment: 0 }));
+        assert_eq!(ram.release_segment(segment), Ok(()));
+        assert_eq!(ram.release_segment(segment), Err(MemoryError::DoubleFree{ segment }));
+        assert_eq!(ram.release_segment(9), Err(MemoryError::UnmappedSegment{ segment: 9 }));
+        assert_eq!(ram.try_get(segment, 0), Err(MemoryError::SegmentFreed{ segment }));
+        assert_eq!(ram.try_duplicate_segment(0, segment), Err(MemoryError::SegmentFreed{ segment }));
+        assert_eq!(ram.used_words(), 1);
+
+        // The id is handed out again.
+        assert_eq!(ram.request_segment(2), Ok(segment));
+    }
+
+    #[test]
+    fn capacity_test(){
+        let mut ram = RAM::new();
+        assert_eq!(ram.request_segment(1 << 32), Err(MemoryError::OutOfMemory{ requested: 1 << 32, available: DEFAULT_CAPACITY }));
+
+        let mut ram = RAM::with_capacity(10, Some(2));
+        ram.request_segment(4).unwrap();
+        assert_eq!(ram.request_segment(7), Err(MemoryError::OutOfMemory{ requested: 7, available: 6 }));
+        ram.request_segment(6).unwrap();
+        assert_eq!(ram.request_segment(0), Err(MemoryError::TooManySegments{ limit: 2 }));
+        assert_eq!(ram.available_words(), 0);
+    }
+
+    #[test]
+    fn map_fault_test(){
+        // A 64-bit guest asking for 2^32 words.
+        let mut cpu = CPU::new(1, 64, 8);
+        let mut ram = RAM::new();
+        ram.request_segment(1).unwrap();
+        cpu.set_register(1, 1 << 32);
+        let map = cpu.build_instruction(CPU_Opcode::MapSeg, 0, 2, 1);
+        assert_eq!(cpu.compute(&mut ram, map), Err(CpuFault::OutOfMemory{ pc: 0, requested: 1 << 32 }));
+
+        let mut ram = RAM::with_capacity(100, Some(1));
+        ram.request_segment(1).unwrap();
+        cpu.set_register(1, 2);
+        let fault = cpu.compute(&mut ram, map).unwrap_err();
+        assert_eq!(fault, CpuFault::TooManySegments{ pc: 0, limit: 1 });
+        assert_eq!(fault.kind(), FaultKind::TooManySegments);
+        assert_ne!(fault.code(), CpuFault::OutOfMemory{ pc: 0, requested: 1 }.code());
+        assert_eq!(fault.to_string(), "all 1 segments are mapped at pc 0");
     }
 }
\ No newline at end of file
@@ -0,0 +1,223 @@
+use std::fmt::{Display, Formatter};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Clock speed meaning "run as fast as the host allows".
+pub const UNLIMITED: u64 = 0;
+
+/// Batch length, in cycles, used when the clock is unlimited.
+const UNLIMITED_BATCH: u64 = 100_000;
+
+/// Throttles an emulated processor to a target clock speed.
+///
+/// Instructions are executed in batches of roughly one millisecond of
+/// emulated time. After each batch the scheduler sleeps until the host has
+/// caught up with the emulated clock, instead of spinning between every
+/// instruction. When the host falls behind instead, e.g. while the CPU is
+/// blocked on input, the emulated clock is moved up to within a batch of it
+/// rather than running unthrottled until it has caught up.
+pub struct Scheduler {
+    clock_speed: u64, // hertz, or UNLIMITED
+    batch_cycles: u64,
+    start: Instant,
+    skipped: Duration, // host time the emulated clock was moved up by
+    cycles: u64,
+    instructions: u64,
+    batch_end: u64
+}
+
+/// Statistics of a run, comparing the achieved speed against the target.
+#[derive(Copy, Clone, Debug)]
+pub struct SchedulerReport {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub elapsed: Duration,
+    pub clock_speed: u64
+}
+
+impl Scheduler {
+    pub fn new(clock_speed: u64) -> Self {
+        let batch_cycles = match clock_speed {
+            UNLIMITED => { UNLIMITED_BATCH },
+            hz => { (hz / 1000).max(1) }
+        };
+
+        Scheduler {
+            clock_speed,
+            batch_cycles,
+            start: Instant::now(),
+            skipped: Duration::ZERO,
+            cycles: 0,
+            instructions: 0,
+            batch_end: batch_cycles
+        }
+    }
+
+    /// Restarts the emulated clock.
+    pub fn reset(&mut self) {
+        self.start = Instant::now();
+        self.skipped = Duration::ZERO;
+        self.cycles = 0;
+        self.instructions = 0;
+        self.batch_end = self.batch_cycles;
+    }
+
+    /// Records one executed instruction that took `cycles` cycles, sleeping
+    /// at the end of a batch if the emulated clock is ahead of the host.
+    pub fn account(&mut self, cycles: u64) {
+        self.instructions += 1;
+        self.cycles += cycles;
+
+        if self.cycles >= self.batch_end {
+            self.batch_end = self.cycles + self.batch_cycles;
+            self.throttle();
+        }
+    }
+
+    fn throttle(&mut self) {
+        if let Some(delay) = self.pace(self.start.elapsed()) {
+            sleep(delay);
+        }
+    }
+
+    /// How long to sleep when `elapsed` host time has passed since the
+    /// start, if at all. A host more than a batch ahead of the emulated
+    /// clock is let off all but the last batch.
+    fn pace(&mut self, elapsed: Duration) -> Option<Duration> {
+        if self.clock_speed == UNLIMITED {
+            return None;
+        }
+
+        let emulated = self.duration_of(self.cycles) + self.skipped;
+        let batch = self.duration_of(self.batch_cycles);
+        if elapsed > emulated + batch {
+            self.skipped += elapsed - emulated - batch;
+            return None;
+        }
+        emulated.checked_sub(elapsed).filter(|delay| !delay.is_zero())
+    }
+
+    /// The emulated time `cycles` cycles take.
+    fn duration_of(&self, cycles: u64) -> Duration {
+        Duration::from_nanos((cycles as u128 * 1_000_000_000 / self.clock_speed as u128) as u64)
+    }
+
+    pub fn report(&self) -> SchedulerReport {
+        SchedulerReport {
+            instructions: self.instructions,
+            cycles: self.cycles,
+            elapsed: self.start.elapsed(),
+            clock_speed: self.clock_speed
+        }
+    }
+}
+
+impl SchedulerReport {
+    /// Millions of instructions executed per second of host time.
+    pub fn achieved_mips(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
+        }
+        self.instructions as f64 / seconds / 1_000_000.0
+    }
+
+    /// The MIPS the target clock allows, or None when unlimited.
+    pub fn target_mips(&self) -> Option<f64> {
+        if self.clock_speed == UNLIMITED || self.cycles == 0 {
+            return None;
+        }
+        let cycles_per_instruction = self.cycles as f64 / self.instructions as f64;
+        Some(self.clock_speed as f64 / cycles_per_instruction / 1_000_000.0)
+    }
+}
+
+impl Display for SchedulerReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} instructions, {} cycles in {:?}: {:.3} MIPS",
+               self.instructions, self.cycles, self.elapsed, self.achieved_mips())?;
+        match self.target_mips() {
+            Some(target) => { write!(f, " (target {:.3} MIPS)", target) },
+            None => { write!(f, " (unlimited)") }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::time::Duration;
+    use crate::scheduler::{Scheduler, SchedulerReport, UNLIMITED, UNLIMITED_BATCH};
+
+    #[test]
+    fn batch_size_test(){
+        // About a millisecond of emulated time, and at least one cycle.
+        assert_eq!(Scheduler::new(4_770_000).batch_cycles, 4770);
+        assert_eq!(Scheduler::new(500).batch_cycles, 1);
+        assert_eq!(Scheduler::new(UNLIMITED).batch_cycles, UNLIMITED_BATCH);
+
+        let mut scheduler = Scheduler::new(1_000_000);
+        scheduler.account(600);
+        assert_eq!(scheduler.batch_end, 1000);
+        scheduler.account(600);
+        assert_eq!(scheduler.batch_end, 2200);
+    }
+
+    #[test]
+    fn pace_test(){
+        // 100 kHz, so a batch is 100 cycles or 1 ms.
+        let mut scheduler = Scheduler::new(100_000);
+        scheduler.cycles = 5000;
+        assert_eq!(scheduler.pace(Duration::from_millis(20)), Some(Duration::from_millis(30)));
+        assert_eq!(scheduler.pace(Duration::from_millis(50)), None);
+        // Up to a batch behind is caught up on.
+        assert_eq!(scheduler.pace(Duration::from_millis(51)), None);
+        assert_eq!(scheduler.skipped, Duration::ZERO);
+
+        scheduler.cycles = 5100;
+        assert_eq!(scheduler.pace(Duration::from_millis(50)), Some(Duration::from_millis(1)));
+
+        let mut unlimited = Scheduler::new(UNLIMITED);
+        unlimited.cycles = 1 << 40;
+        assert_eq!(unlimited.pace(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn catch_up_test(){
+        let mut scheduler = Scheduler::new(100_000);
+        scheduler.cycles = 5000;
+        // Blocked for 10 ms longer than the program ran: all but the last
+        // batch of that is forgiven.
+        assert_eq!(scheduler.pace(Duration::from_millis(60)), None);
+        assert_eq!(scheduler.skipped, Duration::from_millis(9));
+
+        // So the next batches are throttled again.
+        scheduler.cycles = 5100;
+        assert_eq!(scheduler.pace(Duration::from_millis(60)), None);
+        scheduler.cycles = 5200;
+        assert_eq!(scheduler.pace(Duration::from_millis(60)), Some(Duration::from_millis(1)));
+
+        scheduler.reset();
+        assert_eq!(scheduler.skipped, Duration::ZERO);
+    }
+
+    #[test]
+    fn report_test(){
+        let mut scheduler = Scheduler::new(UNLIMITED);
+        for _ in 0..1000 {
+            scheduler.account(4);
+        }
+        let report = scheduler.report();
+        assert_eq!(report.instructions, 1000);
+        assert_eq!(report.cycles, 4000);
+        assert_eq!(report.target_mips(), None);
+        assert!(report.to_string().ends_with("(unlimited)"));
+
+        let report = SchedulerReport { instructions: 500, cycles: 5000, elapsed: Duration::from_millis(50), clock_speed: 100_000 };
+        assert_eq!(report.target_mips(), Some(0.01));
+        assert_eq!(report.achieved_mips(), 0.01);
+
+        scheduler.reset();
+        assert_eq!(scheduler.report().cycles, 0);
+        assert_eq!(scheduler.batch_end, UNLIMITED_BATCH);
+    }
+}
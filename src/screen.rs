@@ -1,11 +1,13 @@
 use crossbeam::channel::Receiver;
 use rand::Rng;
 use rand::rngs::ThreadRng;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
-use sdl2::render::{WindowCanvas};
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::WindowContext;
 use sdl2::Sdl;
 use crate::machine::VideoOutWrapper;
+use crate::video_mode::dirty_rows;
 // use winit::dpi::LogicalSize;
 // use winit::event_loop::EventLoop;
 // use winit::window::{Window, WindowBuilder};
@@ -17,23 +19,31 @@ use crate::machine::VideoOutWrapper;
 /// * `pixel_height`: The height of the individual pixels of the monitor.
 /// * `width`: The pixel count width of the monitor.
 /// * `height`:  The pixel count height of the monitor.
-/// * `color_width`
-/// * `pixels`: Array representing the pixels to be rendered.
-pub struct Screen {
+/// * `pixels`: The last frame drawn, three bytes (red, green, blue) per pixel.
+/// * `texture`: A streaming texture holding `pixels`, scaled to the window.
+///   It is made by `texture_creator`, which must outlive the screen.
+///
+/// Each frame is decoded with the video mode it was sent with, and the
+/// window is resized when the mode's resolution changes. Only the rows that
+/// changed since the last frame are copied into the texture.
+pub struct Screen<'a> {
+    pixel_width: u32,
+    pixel_height: u32,
     width: u32,
     height: u32,
-    color_width: u64,
     clear_color: Color,
-    pixels: Vec<Vec<(Rect, [u8; 3])>>,
+    pixels: Vec<u8>,
     canvas: WindowCanvas,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    texture: Texture<'a>,
     rng: ThreadRng,
     receiver: Receiver<VideoOutWrapper>
 }
 
 
-impl Screen{
-    pub fn new(pixel_width: u32, pixel_height: u32, x_size: u32, y_size: u32, color_width: u64, sdl_context: &Sdl, receiver: Receiver<VideoOutWrapper>) -> Self{
-
+impl<'a> Screen<'a>{
+    /// Opens the window a screen of the given size draws into.
+    pub fn open_window(pixel_width: u32, pixel_height: u32, x_size: u32, y_size: u32, sdl_context: &Sdl) -> WindowCanvas{
         let video_subsystem = sdl_context.video().unwrap();
 
         let width = pixel_width * x_size;
@@ -44,124 +54,82 @@ impl Screen{
             .build()
             .unwrap();
 
-        let canvas = window.into_canvas().build().unwrap();
+        window.into_canvas().build().unwrap()
+    }
 
+    /// A screen drawing into `canvas`, which `texture_creator` was made from.
+    pub fn new(pixel_width: u32, pixel_height: u32, x_size: u32, y_size: u32, canvas: WindowCanvas, texture_creator: &'a TextureCreator<WindowContext>, receiver: Receiver<VideoOutWrapper>) -> Self{
         let clear_color = Color::RGB(0,0,0);
 
         let rng = rand::thread_rng();
 
-        let mut pixels = Vec::new();
-
-        for y in 0..y_size{
-            let mut buffer = Vec::new();
-            for x in 0..x_size{
-                let rect: Rect = Rect::new(
-                    (x * pixel_width) as i32,
-                    (y * pixel_height) as i32,
-                    pixel_width,
-                    pixel_height
-                );
-
-                buffer.push((rect, [0,0,0]));
-            }
-            pixels.push(buffer);
-        }
-
-        println!("{} {}", pixels.len(), pixels[0].len());
+        let (texture, pixels) = Self::blank_texture(texture_creator, x_size, y_size);
 
         Screen{
+            pixel_width,
+            pixel_height,
             width: x_size,
             height: y_size,
-            color_width,
             pixels,
             clear_color,
             canvas,
+            texture_creator,
+            texture,
             rng,
             receiver
         }
     }
 
-    pub unsafe fn draw(&mut self) {
-        self.canvas.set_draw_color(self.clear_color);
-        self.canvas.clear();
-        
-        if self.receiver.len() > 0 {
-            
-            //println!("Debug1");
-            
-            let pixels = self.receiver.iter().nth(0).unwrap().data;
-
-            //println!("Debug2");
-            //println!("{}", pixels.len());
-            
-            if (*pixels).len() == self.pixels.len() * self.pixels[0].len() * 3 {
-
-                //println!("{}", (*pixels).len());
-                for mut pixel in (0..(*pixels).len()).step_by(3){
-                    //println!("{}", pixel);
-                    let x = (pixel / 3) % (self.pixels[0].len());
-                    let y = (pixel / 3) / (self.pixels[0].len());
-
-                    //println!("{pixel}");
-                    self.pixels[y][x].1 = [(*pixels)[pixel] as u8, (*pixels)[pixel + 1] as u8, (*pixels)[pixel + 2] as u8];
-                    
-                }
-            }
-            else{
-                for y in 0..self.height as usize{
-                    for x in 0..self.width as usize{
-                        let red = self.rng.gen::<u8>();
-                        let green = self.rng.gen::<u8>();
-                        let blue = self.rng.gen::<u8>();
-                
-                        self.pixels[y][x].1 = [red, green, blue];
-                    }
-                }
-            }
-            
+    /// A black texture of the given size, and its pixels.
+    fn blank_texture(texture_creator: &'a TextureCreator<WindowContext>, x_size: u32, y_size: u32) -> (Texture<'a>, Vec<u8>) {
+        let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, x_size, y_size).unwrap();
+        let pixels = vec![0; (x_size * y_size * 3) as usize];
+        texture.update(None, &pixels, x_size as usize * 3).unwrap();
+        (texture, pixels)
+    }
+
+    /// Changes the resolution, resizing the window and texture to fit.
+    fn resize(&mut self, x_size: u32, y_size: u32) {
+        // The old texture is dropped, and destroyed, here.
+        (self.texture, self.pixels) = Self::blank_texture(self.texture_creator, x_size, y_size);
+        self.width = x_size;
+        self.height = y_size;
+        let _ = self.canvas.window_mut().set_size(self.pixel_width * x_size, self.pixel_height * y_size);
+    }
+
+    /// Copies the rows of `pixels` that differ from the last frame into the
+    /// texture.
+    fn update(&mut self, pixels: Vec<u8>) {
+        let pitch = self.width as usize * 3;
+        if let Some(rows) = dirty_rows(&self.pixels, &pixels, pitch) {
+            let rect = Rect::new(0, rows.start as i32, self.width, rows.len() as u32);
+            self.texture.update(rect, &pixels[rows.start * pitch..rows.end * pitch], pitch).unwrap();
+            self.pixels = pixels;
         }
+    }
 
-        for y in 0..self.height as usize{
-            for x in 0..self.width as usize{
-                //println!("{} {}", x, y);
-                
-                self.canvas.set_draw_color(
-                    Color::RGB(
-                        self.pixels[y][x].1[0],
-                        self.pixels[y][x].1[1],
-                        self.pixels[y][x].1[2],
-                    )
-                );
-                self.canvas.fill_rect(
-                    self.pixels[y][x].0
-                ).unwrap();
+    pub fn draw(&mut self) {
+        if let Ok(frame) = self.receiver.try_recv() {
+            let (x_size, y_size) = (frame.mode.width as u32, frame.mode.height as u32);
+            if (x_size, y_size) != (self.width, self.height) {
+                self.resize(x_size, y_size);
             }
+
+            let pixels = match frame.mode.decode(&frame.data, &frame.palette) {
+                Some(colours) => { colours.concat() }
+                None => {
+                    // A frame that doesn't match its mode shows up as noise.
+                    let mut noise = vec![0; self.pixels.len()];
+                    self.rng.fill(&mut noise[..]);
+                    noise
+                }
+            };
+            self.update(pixels);
         }
 
-        // 
-        // match signal{
-        //     Some(s) => unsafe {
-        //         for y in 0..self.height as usize{
-        //             for x in 0..self.width as usize{
-        //                 //println!("{} {}", x, y);
-        //                 
-        //                 self.canvas.set_draw_color(
-        //                     Color::RGB(
-        //                         (*s.signal).video_out[y][x][0],
-        //                         (*s.signal).video_out[y][x][1],
-        //                         (*s.signal).video_out[y][x][2],
-        //                     )
-        //                 );
-        //                 self.canvas.fill_rect(
-        //                     self.pixels[y][x]
-        //                 ).unwrap();
-        //             }
-        //         }
-        //     }
-        //     None => { 
-        //         
-        //     }
-        // }
+        self.canvas.set_draw_color(self.clear_color);
+        self.canvas.clear();
+        self.canvas.copy(&self.texture, None, None).unwrap();
         self.canvas.present();
     }
 }
\ No newline at end of file
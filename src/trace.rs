@@ -0,0 +1,470 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use crate::cpu::CPU;
+
+/// The first bytes of every trace file.
+const MAGIC: &[u8; 4] = b"WTRC";
+const VERSION: u8 = 2;
+
+// Every executed instruction is a STEP event followed by the events it caused.
+const STEP: u8 = 1;
+const REGISTER: u8 = 2;
+const WRITE: u8 = 3;
+const OUTPUT: u8 = 4;
+const INPUT: u8 = 5;
+const END_OF_INPUT: u8 = 6;
+const FAULT: u8 = 7;
+const MAP: u8 = 8;
+const UNMAP: u8 = 9;
+const LOAD_PROGRAM: u8 = 10;
+const PORT_READ: u8 = 11;
+
+/// Writes an execution trace.
+///
+/// A trace is a header with the register width and the registers when
+/// tracing started, then a stream of tagged events: each executed
+/// instruction's pc and raw word (its operands are decoded when the trace
+/// is read), the registers it changed, the words it stored, the segments it
+/// mapped, unmapped or loaded, the bytes it wrote or read, the I/O ports it
+/// read, and its fault if it raised one. A trapped fault's trap registers
+/// are part of the faulting instruction. Numbers are LEB128 varints, so most
+/// events take a few bytes.
+pub struct TraceWriter {
+    out: BufWriter<Box<dyn Write + Send>>,
+    registers: Vec<u64>,
+    error: Option<std::io::Error>
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+impl TraceWriter {
+    /// Starts a trace of `cpu` written to `out`.
+    pub fn new<W: Write + Send + 'static>(out: W, cpu: &CPU) -> std::io::Result<Self> {
+        let mut writer = TraceWriter {
+            out: BufWriter::new(Box::new(out)),
+            registers: cpu.registers().to_vec(),
+            error: None
+        };
+
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.push(cpu.register_width() as u8);
+        write_varint(&mut header, writer.registers.len() as u64);
+        for register in writer.registers.iter() {
+            write_varint(&mut header, *register);
+        }
+        writer.out.write_all(&header)?;
+        Ok(writer)
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P, cpu: &CPU) -> std::io::Result<Self> {
+        Self::new(File::create(path)?, cpu)
+    }
+
+    /// Writes an event. After the first error nothing more is written, and
+    /// the error is returned by `finish`.
+    fn emit(&mut self, tag: u8, values: &[u64]) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut bytes = vec![tag];
+        for value in values {
+            write_varint(&mut bytes, *value);
+        }
+        if let Err(e) = self.out.write_all(&bytes) {
+            self.error = Some(e);
+        }
+    }
+
+    pub(crate) fn step(&mut self, pc: u64, instruction: u32) {
+        self.emit(STEP, &[pc, instruction as u64]);
+    }
+
+    /// Records the registers that changed since the last call.
+    pub(crate) fn registers(&mut self, registers: &[u64]) {
+        let mut changed = Vec::new();
+        for (i, (old, new)) in self.registers.iter_mut().zip(registers).enumerate() {
+            if old != new {
+                *old = *new;
+                changed.push((i as u64, *new));
+            }
+        }
+        for (i, value) in changed {
+            self.emit(REGISTER, &[i, value]);
+        }
+    }
+
+    pub(crate) fn write(&mut self, segment: usize, index: usize, value: u64) {
+        self.emit(WRITE, &[segment as u64, index as u64, value]);
+    }
+
+    pub(crate) fn map(&mut self, segment: usize, words: usize) {
+        self.emit(MAP, &[segment as u64, words as u64]);
+    }
+
+    pub(crate) fn unmap(&mut self, segment: usize) {
+        self.emit(UNMAP, &[segment as u64]);
+    }
+
+    pub(crate) fn load_program(&mut self, segment: usize) {
+        self.emit(LOAD_PROGRAM, &[segment as u64]);
+    }
+
+    pub(crate) fn port_read(&mut self, port: u64, value: u64) {
+        self.emit(PORT_READ, &[port, value]);
+    }
+
+    pub(crate) fn output(&mut self, byte: u8) {
+        self.emit(OUTPUT, &[byte as u64]);
+    }
+
+    /// Records a byte read by `input`, or `None` for the end of input.
+    pub(crate) fn input(&mut self, byte: Option<u8>) {
+        match byte {
+            Some(byte) => { self.emit(INPUT, &[byte as u64]) },
+            None => { self.emit(END_OF_INPUT, &[]) }
+        }
+    }
+
+    pub(crate) fn fault(&mut self, code: u64) {
+        self.emit(FAULT, &[code]);
+    }
+
+    /// Flushes the trace, returning the first error hit while writing it.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()
+    }
+}
+
+/// The state of the CPU when a trace started.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub register_width: usize,
+    pub registers: Vec<u64>
+}
+
+/// A segment mapped, unmapped or loaded into `m[0]` by an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentChange {
+    Map { segment: usize, words: usize },
+    Unmap { segment: usize },
+    LoadProgram { segment: usize }
+}
+
+/// One executed instruction and what it did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u64,
+    pub instruction: u32,
+    /// The new values of the registers it changed.
+    pub registers: Vec<(usize, u64)>,
+    /// `(segment, index, value)` of every word it stored.
+    pub writes: Vec<(usize, usize, u64)>,
+    pub segments: Vec<SegmentChange>,
+    pub output: Vec<u8>,
+    /// The bytes it read, with `None` for the end of input.
+    pub input: Vec<Option<u8>>,
+    /// `(port, value)` of every I/O port it read.
+    pub port_reads: Vec<(u64, u64)>,
+    /// The code of the fault it raised, if any.
+    pub fault: Option<u64>
+}
+
+/// Reads the records of a trace written by `TraceWriter`.
+pub struct TraceReader<R: Read> {
+    input: R,
+    header: TraceHeader,
+    next: Option<TraceRecord>
+}
+
+fn corrupt(message: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, format!("corrupt trace: {message}"))
+}
+
+fn read_byte<R: Read>(input: &mut R) -> std::io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match input.read_exact(&mut byte) {
+        Ok(()) => { Ok(Some(byte[0])) }
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => { Ok(None) }
+        Err(e) => { Err(e) }
+    }
+}
+
+fn read_varint<R: Read>(input: &mut R) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_byte(input)?.ok_or(corrupt("truncated number"))?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(corrupt("number too long"))
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> std::io::Result<Self> {
+        let mut magic = [0u8; 5];
+        input.read_exact(&mut magic)?;
+        if &magic[..4] != MAGIC {
+            return Err(corrupt("not a WARCH trace"));
+        }
+        // Later versions only add events, so older traces still read.
+        if magic[4] == 0 || magic[4] > VERSION {
+            return Err(corrupt(&format!("unsupported version {}", magic[4])));
+        }
+
+        let register_width = read_byte(&mut input)?.ok_or(corrupt("truncated header"))? as usize;
+        let count = read_varint(&mut input)?;
+        let registers = (0..count).map(|_| read_varint(&mut input)).collect::<Result<Vec<_>, _>>()?;
+        Ok(TraceReader {
+            input,
+            header: TraceHeader { register_width, registers },
+            next: None
+        })
+    }
+
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
+    }
+
+    /// Reads events up to the start of the next record, returning the
+    /// record they complete.
+    fn read_record(&mut self) -> std::io::Result<Option<TraceRecord>> {
+        loop {
+            let tag = match read_byte(&mut self.input)? {
+                Some(tag) => { tag },
+                None => { return Ok(self.next.take()) }
+            };
+            if tag == STEP {
+                let pc = read_varint(&mut self.input)?;
+                let instruction = read_varint(&mut self.input)? as u32;
+                let record = TraceRecord { pc, instruction, ..TraceRecord::default() };
+                match self.next.replace(record) {
+                    Some(finished) => { return Ok(Some(finished)) }
+                    None => { continue }
+                }
+            }
+
+            let record = self.next.as_mut().ok_or(corrupt("event before the first instruction"))?;
+            match tag{
+                REGISTER => {
+                    let register = read_varint(&mut self.input)? as usize;
+                    record.registers.push((register, read_varint(&mut self.input)?));
+                }
+                WRITE => {
+                    let segment = read_varint(&mut self.input)? as usize;
+                    let index = read_varint(&mut self.input)? as usize;
+                    record.writes.push((segment, index, read_varint(&mut self.input)?));
+                }
+                MAP => {
+                    let segment = read_varint(&mut self.input)? as usize;
+                    let words = read_varint(&mut self.input)? as usize;
+                    record.segments.push(SegmentChange::Map { segment, words });
+                }
+                UNMAP => {
+                    let segment = read_varint(&mut self.input)? as usize;
+                    record.segments.push(SegmentChange::Unmap { segment });
+                }
+                LOAD_PROGRAM => {
+                    let segment = read_varint(&mut self.input)? as usize;
+                    record.segments.push(SegmentChange::LoadProgram { segment });
+                }
+                PORT_READ => {
+                    let port = read_varint(&mut self.input)?;
+                    record.port_reads.push((port, read_varint(&mut self.input)?));
+                }
+                OUTPUT => { record.output.push(read_varint(&mut self.input)? as u8); }
+                INPUT => { record.input.push(Some(read_varint(&mut self.input)? as u8)); }
+                END_OF_INPUT => { record.input.push(None); }
+                FAULT => { record.fault = Some(read_varint(&mut self.input)?); }
+                _ => { return Err(corrupt(&format!("unknown event {tag}"))) }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = std::io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Every byte read by `input` in a trace, in order, for replaying the run
+/// with `CPU::replay_input`.
+pub fn recorded_input<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<Option<u8>>> {
+    let mut input = Vec::new();
+    for record in TraceReader::open(path)? {
+        input.extend(record?.input);
+    }
+    Ok(input)
+}
+
+/// Every value read by `portin` in a trace, in order, for replaying the run
+/// with `CPU::replay_port_reads`.
+pub fn recorded_port_reads<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u64>> {
+    let mut values = Vec::new();
+    for record in TraceReader::open(path)? {
+        values.extend(record?.port_reads.into_iter().map(|(_, value)| value));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::assembler::{assemble, Assembly};
+    use crate::cpu::{CPU, FaultKind, FaultPolicy};
+    use crate::harddrive::HardDrive;
+    use crate::machine::{Machine, MachineBuilder};
+    use crate::MachinePart::Bus;
+    use crate::ram::RAM;
+    use crate::scheduler::UNLIMITED;
+    use crate::trace::{SegmentChange, TraceReader, TraceWriter, recorded_input, recorded_port_reads};
+
+    fn load(source: &str) -> Machine {
+        let mut machine = MachineBuilder::new()
+            .cpu(CPU::new(UNLIMITED, 32, 8))
+            .ram(RAM::new())
+            .drive(HardDrive::from_bytes(assemble(source).unwrap().to_bytes()))
+            .um_mode(true)
+            .build()
+            .unwrap();
+        machine.load().unwrap();
+        machine
+    }
+
+    #[test]
+    fn record_and_replay_test(){
+        let source = "
+            input r1
+            input r2
+            movi r3, 1
+            map r4, r3
+            store r4, r0, r1
+            halt
+        ";
+        let path = std::env::temp_dir().join(format!("warch-trace-test-{}.wtrc", std::process::id()));
+
+        let mut machine = load(source);
+        let cpu = machine.part_mut::<CPU>().unwrap();
+        cpu.replay_input(vec![Some(b'a')]);
+        let tracer = TraceWriter::create(&path, cpu).unwrap();
+        cpu.set_tracer(Some(tracer));
+        assert_eq!(machine.run(), Ok(()));
+        machine.part_mut::<CPU>().unwrap().take_tracer().unwrap().finish().unwrap();
+
+        let reader = TraceReader::open(&path).unwrap();
+        assert_eq!(reader.header().register_width, 32);
+        let records: Vec<_> = reader.map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[0].input, vec![Some(b'a')]);
+        assert_eq!(records[0].registers, vec![(1, b'a' as u64)]);
+        // The replayed input ran out, which reads as the end of input.
+        assert_eq!(records[1].input, vec![None]);
+        assert_eq!(records[1].registers, vec![(2, 0xffffffff)]);
+        assert_eq!(records[4].pc, 4);
+        assert_eq!(records[4].writes, vec![(1, 0, b'a' as u64)]);
+
+        assert_eq!(recorded_input(&path).unwrap(), vec![Some(b'a'), None]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A bus with one port, which reads as 42.
+    struct Status;
+
+    impl Bus for Status {
+        fn port_read(&mut self, port: u64) -> Option<u64> {
+            if port == 0x14 { Some(42) } else { None }
+        }
+    }
+
+    fn program_ram(assembly: &Assembly) -> RAM {
+        let mut ram = RAM::new();
+        ram.request_segment(assembly.words.len()).unwrap();
+        for (i, word) in assembly.words.iter().enumerate() {
+            ram.set(0, i, *word as u64);
+        }
+        ram
+    }
+
+    fn trapping_cpu(assembly: &Assembly) -> CPU {
+        let mut cpu = CPU::new(UNLIMITED, 32, 8);
+        cpu.set_trap_vector(Some(assembly.labels["handler"] as u64));
+        cpu.set_fault_policy(FaultKind::DivideByZero, FaultPolicy::Trap);
+        cpu
+    }
+
+    #[test]
+    fn segments_traps_and_ports_test(){
+        let assembly = assemble("
+                    movi r1, 1
+                    map r2, r1
+                    map r3, r1
+                    umap r3
+                    li32 r3, 0x70000000 ; halt
+                    store r2, r0, r3
+                    movi r3, 0x14
+                    portin r4, r3
+                    div r4, r4, r0
+            handler:
+                    run r2, r0
+        ").unwrap();
+        let path = std::env::temp_dir().join(format!("warch-trace-segments-test-{}.wtrc", std::process::id()));
+
+        let mut cpu = trapping_cpu(&assembly);
+        let mut ram = program_ram(&assembly);
+        cpu.set_tracer(Some(TraceWriter::create(&path, &cpu).unwrap()));
+        assert_eq!(cpu.run_on(&mut ram, &mut Status), Ok(()));
+        cpu.take_tracer().unwrap().finish().unwrap();
+
+        let records: Vec<_> = TraceReader::open(&path).unwrap().map(|record| record.unwrap()).collect();
+        let segments: Vec<_> = records.iter().flat_map(|record| record.segments.clone()).collect();
+        assert_eq!(segments, vec![
+            SegmentChange::Map { segment: 1, words: 1 },
+            SegmentChange::Map { segment: 2, words: 1 },
+            SegmentChange::Unmap { segment: 2 },
+            SegmentChange::LoadProgram { segment: 1 }
+        ]);
+
+        let read = records.iter().find(|record| !record.port_reads.is_empty()).unwrap();
+        assert_eq!(read.port_reads, vec![(0x14, 42)]);
+        assert_eq!(read.registers, vec![(4, 42)]);
+
+        // The trap frame is recorded with the instruction that faulted.
+        let div = records.iter().find(|record| record.fault.is_some()).unwrap();
+        assert_eq!(div.fault, Some(FaultKind::DivideByZero as u64));
+        assert!(div.registers.contains(&(5, FaultKind::DivideByZero as u64)));
+        assert!(div.registers.contains(&(6, div.pc)));
+        assert!(div.registers.contains(&(7, div.instruction as u64)));
+
+        // Replaying feeds the recorded port reads back instead of the bus's.
+        assert_eq!(recorded_port_reads(&path).unwrap(), vec![42]);
+        let mut replay = trapping_cpu(&assembly);
+        replay.replay_port_reads(vec![7]);
+        assert_eq!(replay.run_on(&mut program_ram(&assembly), &mut Status), Ok(()));
+        assert_eq!(replay.registers()[4], 7);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
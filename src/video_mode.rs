@@ -0,0 +1,254 @@
+use std::ops::Range;
+use crate::display_data_helper::PALETTE;
+
+/// How the words of the framebuffer encode its pixels.
+///
+/// Pixels are stored row by row. The indexed and monochrome formats pack
+/// as many pixels as fit into each word, lowest bits first, and rows are
+/// not padded to a whole word.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFormat{
+    /// Three words per pixel: red, green and blue.
+    Rgb,
+    /// One word per pixel, 0xRRGGBB.
+    PackedRgb,
+    /// 8-bit indices into the palette.
+    Indexed8,
+    /// 4-bit indices into the first 16 colours of the palette.
+    Indexed4,
+    /// One bit per pixel, drawn green on black.
+    Mono
+}
+
+impl PixelFormat{
+    pub fn from_code(code: u64) -> Option<Self> {
+        match code{
+            0 => { Some(PixelFormat::Rgb) }
+            1 => { Some(PixelFormat::PackedRgb) }
+            2 => { Some(PixelFormat::Indexed8) }
+            3 => { Some(PixelFormat::Indexed4) }
+            4 => { Some(PixelFormat::Mono) }
+            _ => { None }
+        }
+    }
+
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+
+    /// The bits taken by a pixel, or by each channel of an `Rgb` pixel.
+    pub fn bits(self) -> usize {
+        match self{
+            PixelFormat::Rgb => { 8 }
+            PixelFormat::PackedRgb => { 24 }
+            PixelFormat::Indexed8 => { 8 }
+            PixelFormat::Indexed4 => { 4 }
+            PixelFormat::Mono => { 1 }
+        }
+    }
+}
+
+/// The colours of an unset and a set `Mono` pixel.
+pub const MONO_COLOURS: [[u8; 3]; 2] = [[0x00, 0x00, 0x00], [0x33, 0xff, 0x33]];
+
+/// The number of colours in the palette.
+pub const PALETTE_SIZE: usize = 256;
+
+/// The palette the GPU starts with: the 16 text colours, a 6x6x6 colour
+/// cube and a ramp of 24 greys.
+pub fn default_palette() -> Vec<[u8; 3]> {
+    let levels = [0x00, 0x5f, 0x87, 0xaf, 0xd7, 0xff];
+    let mut palette = PALETTE.to_vec();
+    for i in 0..216 {
+        palette.push([levels[i / 36], levels[i / 6 % 6], levels[i % 6]]);
+    }
+    for i in 0..24 {
+        let grey = (8 + i * 10) as u8;
+        palette.push([grey; 3]);
+    }
+    palette
+}
+
+/// The layout of a framebuffer: its format and size in pixels, and the
+/// width of the words it is stored in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VideoMode{
+    pub format: PixelFormat,
+    pub width: usize,
+    pub height: usize,
+    pub word_bits: usize
+}
+
+impl VideoMode{
+    pub fn new(format: PixelFormat, width: usize, height: usize, word_bits: usize) -> Self{
+        VideoMode{ format, width, height, word_bits }
+    }
+
+    /// Whether a pixel fits in a word of this width.
+    pub fn is_valid(&self) -> bool {
+        self.format.bits() <= self.word_bits
+    }
+
+    pub fn pixels_per_word(&self) -> usize {
+        match self.format{
+            PixelFormat::Rgb | PixelFormat::PackedRgb => { 1 }
+            format => { (self.word_bits / format.bits()).max(1) }
+        }
+    }
+
+    /// The number of words the framebuffer takes.
+    pub fn frame_words(&self) -> usize {
+        let pixels = self.width * self.height;
+        match self.format{
+            PixelFormat::Rgb => { pixels * 3 }
+            _ => { pixels.div_ceil(self.pixels_per_word()) }
+        }
+    }
+
+    /// Sets pixel `x`, `y` to colour `colour` of `palette`. Indexed formats
+    /// store the index itself, and `Mono` sets any colour but 0.
+    pub fn set_pixel(&self, frame: &mut [u64], x: usize, y: usize, colour: usize, palette: &[[u8; 3]]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let pixel = y * self.width + x;
+        let [red, green, blue] = palette.get(colour).copied().unwrap_or([0; 3]);
+
+        match self.format{
+            PixelFormat::Rgb => {
+                if let Some(words) = frame.get_mut(pixel * 3..pixel * 3 + 3) {
+                    words.copy_from_slice(&[red as u64, green as u64, blue as u64]);
+                }
+            }
+            PixelFormat::PackedRgb => {
+                if let Some(word) = frame.get_mut(pixel) {
+                    *word = (red as u64) << 16 | (green as u64) << 8 | blue as u64;
+                }
+            }
+            format => {
+                let value = if format == PixelFormat::Mono { (colour != 0) as u64 } else { colour as u64 };
+                let per_word = self.pixels_per_word();
+                let shift = (pixel % per_word) * format.bits();
+                let mask = (1u64 << format.bits()) - 1;
+                if let Some(word) = frame.get_mut(pixel / per_word) {
+                    *word = *word & !(mask << shift) | (value & mask) << shift;
+                }
+            }
+        }
+    }
+
+    /// The colours of every pixel of `frame`, row by row, or `None` if the
+    /// frame isn't the size this mode needs.
+    pub fn decode(&self, frame: &[u64], palette: &[[u8; 3]]) -> Option<Vec<[u8; 3]>> {
+        if frame.len() != self.frame_words() {
+            return None;
+        }
+        let pixels = (0..self.width * self.height).map(|pixel| {
+            match self.format{
+                PixelFormat::Rgb => {
+                    [frame[pixel * 3] as u8, frame[pixel * 3 + 1] as u8, frame[pixel * 3 + 2] as u8]
+                }
+                PixelFormat::PackedRgb => {
+                    let word = frame[pixel];
+                    [(word >> 16) as u8, (word >> 8) as u8, word as u8]
+                }
+                PixelFormat::Mono => {
+                    MONO_COLOURS[self.index(frame, pixel)]
+                }
+                _ => {
+                    palette.get(self.index(frame, pixel)).copied().unwrap_or([0; 3])
+                }
+            }
+        }).collect();
+        Some(pixels)
+    }
+
+    /// The palette index of a pixel of a packed format.
+    fn index(&self, frame: &[u64], pixel: usize) -> usize {
+        let per_word = self.pixels_per_word();
+        let shift = (pixel % per_word) * self.format.bits();
+        (frame[pixel / per_word] >> shift & ((1u64 << self.format.bits()) - 1)) as usize
+    }
+}
+
+/// The range of rows, `pitch` bytes each, that differ between two decoded
+/// frames, or `None` if they are the same. Frames of different sizes differ
+/// in every row of the new one.
+pub fn dirty_rows(old: &[u8], new: &[u8], pitch: usize) -> Option<Range<usize>> {
+    if pitch == 0 {
+        return None;
+    }
+    if old.len() != new.len() {
+        return Some(0..new.len() / pitch);
+    }
+    let changed = |(old, new): (&[u8], &[u8])| old != new;
+    let rows = || old.chunks(pitch).zip(new.chunks(pitch));
+    let first = rows().position(changed)?;
+    let last = rows().rposition(changed)?;
+    Some(first..last + 1)
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::display_data_helper::PALETTE;
+    use crate::video_mode::{default_palette, dirty_rows, PixelFormat, VideoMode, MONO_COLOURS, PALETTE_SIZE};
+
+    #[test]
+    fn decode_test(){
+        let palette = default_palette();
+        assert_eq!(palette.len(), PALETTE_SIZE);
+
+        let rgb = VideoMode::new(PixelFormat::Rgb, 2, 1, 32);
+        assert_eq!(rgb.decode(&[1, 2, 3, 4, 5, 6], &palette), Some(vec![[1, 2, 3], [4, 5, 6]]));
+        assert_eq!(rgb.decode(&[1, 2, 3], &palette), None);
+
+        let packed = VideoMode::new(PixelFormat::PackedRgb, 1, 1, 32);
+        assert_eq!(packed.decode(&[0x123456], &palette), Some(vec![[0x12, 0x34, 0x56]]));
+
+        // 10 pixels, two to an 8-bit word.
+        let indexed = VideoMode::new(PixelFormat::Indexed4, 5, 2, 8);
+        assert_eq!(indexed.frame_words(), 5);
+        let colours = indexed.decode(&[0x21, 0, 0, 0, 0x0f], &palette).unwrap();
+        assert_eq!(&colours[0..3], &[PALETTE[1], PALETTE[2], PALETTE[0]]);
+        assert_eq!(&colours[8..10], &[PALETTE[15], PALETTE[0]]);
+
+        let mono = VideoMode::new(PixelFormat::Mono, 40, 1, 32);
+        assert_eq!(mono.frame_words(), 2);
+        let colours = mono.decode(&[0b10, 0x80], &palette).unwrap();
+        assert_eq!(&colours[0..2], &[MONO_COLOURS[0], MONO_COLOURS[1]]);
+        assert_eq!(colours[39], MONO_COLOURS[1]);
+    }
+
+    #[test]
+    fn set_pixel_test(){
+        let palette = default_palette();
+        for format in [PixelFormat::Rgb, PixelFormat::PackedRgb, PixelFormat::Indexed8, PixelFormat::Indexed4, PixelFormat::Mono] {
+            let mode = VideoMode::new(format, 7, 3, 16);
+            assert_eq!(PixelFormat::from_code(format.code()), Some(format));
+            assert_eq!(mode.is_valid(), format != PixelFormat::PackedRgb);
+
+            let mut frame = vec![0; mode.frame_words()];
+            mode.set_pixel(&mut frame, 6, 2, 1, &palette);
+            mode.set_pixel(&mut frame, 5, 2, 15, &palette);
+            mode.set_pixel(&mut frame, 5, 2, 0, &palette);
+            let colours = mode.decode(&frame, &palette).unwrap();
+            let set = if format == PixelFormat::Mono { MONO_COLOURS[1] } else { PALETTE[1] };
+            let unset = if format == PixelFormat::Mono { MONO_COLOURS[0] } else { PALETTE[0] };
+            assert_eq!(colours[20], set);
+            assert!(colours[..20].iter().all(|colour| *colour == unset));
+        }
+    }
+
+    #[test]
+    fn dirty_rows_test(){
+        // Four rows of two bytes.
+        let old = [0u8; 8];
+        assert_eq!(dirty_rows(&old, &old, 2), None);
+        assert_eq!(dirty_rows(&old, &[0, 0, 1, 0, 0, 0, 0, 0], 2), Some(1..2));
+        assert_eq!(dirty_rows(&old, &[0, 0, 0, 1, 0, 0, 1, 0], 2), Some(1..4));
+        assert_eq!(dirty_rows(&old, &[1, 0, 0, 0, 0, 0, 0, 1], 2), Some(0..4));
+        // A resized frame is redrawn whole.
+        assert_eq!(dirty_rows(&old, &[0; 6], 2), Some(0..3));
+        assert_eq!(dirty_rows(&old, &old, 0), None);
+    }
+}